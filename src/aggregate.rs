@@ -0,0 +1,360 @@
+extern crate chrono;
+extern crate chrono_tz;
+
+use self::chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, TimeZone};
+use self::chrono_tz::Etc::UTC;
+use self::chrono_tz::Tz;
+use std::collections::BTreeMap;
+
+use crate::date_time_tz::DateTimeTz;
+
+/// Specify how records are grouped into buckets by their timestamps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bucket {
+    /// Buckets of a fixed length, aligned to the Unix epoch. The length is taken to the
+    /// millisecond; a length of zero or less puts each distinct timestamp into its own bucket.
+    Fixed(Duration),
+
+    /// Buckets that follow the calendar in the time zone that each record was made in. A record
+    /// made at 23:00 in US/Central on the 15th lands in the bucket for the 15th, even though it
+    /// is already the 16th in UTC.
+    Calendar(CalendarUnit),
+
+    /// Buckets that follow the calendar in the specified time zone.
+    CalendarIn(CalendarUnit, Tz),
+}
+
+/// A unit of the calendar, for calendar bucketing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CalendarUnit {
+    Day,
+
+    /// An ISO week, which starts on Monday.
+    Week,
+
+    Month,
+
+    /// A quarter of the year, starting in January, April, July, or October.
+    Quarter,
+
+    Year,
+}
+
+impl Bucket {
+    /// Find the start of the bucket that contains the specified time.
+    pub fn start_of(&self, time: &DateTimeTz) -> DateTimeTz {
+        match self {
+            Bucket::Fixed(length) => {
+                let length = length.num_milliseconds();
+                if length <= 0 {
+                    return time.clone();
+                }
+                let millis = time.0.timestamp_millis();
+                let start = millis - millis.rem_euclid(length);
+                DateTimeTz(UTC.timestamp_millis_opt(start).unwrap())
+            }
+            Bucket::Calendar(unit) => unit.start_in(time, time.0.timezone()),
+            Bucket::CalendarIn(unit, tz) => unit.start_in(time, *tz),
+        }
+    }
+
+    /// Find the start of the bucket that contains the specified time, and the start of the next
+    /// bucket. When every timestamp has a bucket of its own, both are the time itself.
+    pub fn bounds_of(&self, time: &DateTimeTz) -> (DateTimeTz, DateTimeTz) {
+        match self {
+            Bucket::Fixed(length) => {
+                let start = self.start_of(time);
+                if length.num_milliseconds() <= 0 {
+                    return (start.clone(), start);
+                }
+                let end = start.map(|t| t + Duration::milliseconds(length.num_milliseconds()));
+                (start, end)
+            }
+            Bucket::Calendar(unit) => unit.bounds_in(time, time.0.timezone()),
+            Bucket::CalendarIn(unit, tz) => unit.bounds_in(time, *tz),
+        }
+    }
+}
+
+impl CalendarUnit {
+    /// Find the start of the calendar unit that contains `time`, as the calendar is observed in
+    /// `tz`. The start is the first instant of the first local day of the unit, which is
+    /// ordinarily midnight, but may be later if the time zone skips over midnight on that day.
+    pub fn start_in(&self, time: &DateTimeTz, tz: Tz) -> DateTimeTz {
+        let date = self.first_day(time.0.with_timezone(&tz).date_naive());
+        DateTimeTz(start_of_day(tz, date))
+    }
+
+    /// Find the start of the calendar unit that contains `time`, and the start of the next one,
+    /// as the calendar is observed in `tz`. Around daylight saving time transitions the span
+    /// between the two is not a whole number of 24-hour days; a day may last 23 or 25 hours.
+    pub fn bounds_in(&self, time: &DateTimeTz, tz: Tz) -> (DateTimeTz, DateTimeTz) {
+        let first = self.first_day(time.0.with_timezone(&tz).date_naive());
+        let next = self.next_first_day(first);
+        (
+            DateTimeTz(start_of_day(tz, first)),
+            DateTimeTz(start_of_day(tz, next)),
+        )
+    }
+
+    /// The first day of the calendar unit that contains `date`.
+    fn first_day(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            CalendarUnit::Day => date,
+            CalendarUnit::Week => {
+                date - Duration::days(i64::from(date.weekday().num_days_from_monday()))
+            }
+            CalendarUnit::Month => date.with_day(1).unwrap(),
+            CalendarUnit::Quarter => {
+                NaiveDate::from_ymd_opt(date.year(), (date.month0() / 3) * 3 + 1, 1).unwrap()
+            }
+            CalendarUnit::Year => date.with_ordinal(1).unwrap(),
+        }
+    }
+
+    /// The first day of the calendar unit after the one that starts on `first`.
+    fn next_first_day(&self, first: NaiveDate) -> NaiveDate {
+        let add_months = |months: u32| {
+            let month0 = first.month0() + months;
+            NaiveDate::from_ymd_opt(first.year() + (month0 / 12) as i32, month0 % 12 + 1, 1)
+                .unwrap()
+        };
+        match self {
+            CalendarUnit::Day => first + Duration::days(1),
+            CalendarUnit::Week => first + Duration::days(7),
+            CalendarUnit::Month => add_months(1),
+            CalendarUnit::Quarter => add_months(3),
+            CalendarUnit::Year => NaiveDate::from_ymd_opt(first.year() + 1, 1, 1).unwrap(),
+        }
+    }
+}
+
+/// The first instant of a local day in the time zone. When midnight is repeated, this is the
+/// earlier of the two; when midnight is skipped, this is the first local time after it that
+/// exists.
+fn start_of_day(tz: Tz, date: NaiveDate) -> DateTime<Tz> {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    // Time zone transitions happen on quarter hours, and never skip more than a day.
+    for step in 0..=(4 * 48) {
+        match tz.from_local_datetime(&(midnight + Duration::minutes(15 * step))) {
+            LocalResult::Single(t) => return t,
+            LocalResult::Ambiguous(earliest, _) => return earliest,
+            LocalResult::None => continue,
+        }
+    }
+    tz.from_utc_datetime(&midnight)
+}
+
+/// A function that reduces all of the values in a bucket to a single value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reducer {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Mean,
+
+    /// The value of the record with the earliest timestamp in the bucket.
+    First,
+
+    /// The value of the record with the latest timestamp in the bucket.
+    Last,
+}
+
+/// A running summary of the values in a bucket, from which any `Reducer` can be computed. Values
+/// must be added in timestamp order for `first` and `last` to be meaningful.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    pub first: f64,
+    pub last: f64,
+}
+
+impl Summary {
+    /// Start a summary from a single value.
+    pub fn new(value: f64) -> Summary {
+        Summary {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+            first: value,
+            last: value,
+        }
+    }
+
+    /// Add another value to the summary.
+    pub fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.last = value;
+    }
+
+    /// Reduce the summary to a single value.
+    pub fn reduce(&self, reducer: Reducer) -> f64 {
+        match reducer {
+            Reducer::Count => self.count as f64,
+            Reducer::Sum => self.sum,
+            Reducer::Min => self.min,
+            Reducer::Max => self.max,
+            Reducer::Mean => self.sum / self.count as f64,
+            Reducer::First => self.first,
+            Reducer::Last => self.last,
+        }
+    }
+}
+
+/// Group `(timestamp, value)` pairs, which must already be in timestamp order, into buckets and
+/// summarize each bucket. The buckets are returned in order of their start times.
+pub fn summarize<I>(bucket: &Bucket, values: I) -> Vec<(DateTimeTz, Summary)>
+where
+    I: IntoIterator<Item = (DateTimeTz, f64)>,
+{
+    let mut results: BTreeMap<DateTimeTz, Summary> = BTreeMap::new();
+    for (time, value) in values {
+        let start = bucket.start_of(&time);
+        match results.get_mut(&start) {
+            Some(summary) => summary.add(value),
+            None => {
+                results.insert(start, Summary::new(value));
+            }
+        }
+    }
+    results.into_iter().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{summarize, Bucket, CalendarUnit, Reducer};
+    use crate::date_time_tz::DateTimeTz;
+    use chrono::{Duration, TimeZone};
+    use chrono_tz::Etc::UTC;
+    use chrono_tz::Pacific::Apia;
+    use chrono_tz::US::Central;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTimeTz {
+        DateTimeTz(UTC.with_ymd_and_hms(y, m, d, h, min, 0).unwrap())
+    }
+
+    #[test]
+    fn it_finds_fixed_bucket_starts() {
+        let bucket = Bucket::Fixed(Duration::minutes(15));
+        assert_eq!(
+            bucket.start_of(&utc(2019, 5, 15, 14, 44)),
+            utc(2019, 5, 15, 14, 30)
+        );
+        assert_eq!(
+            bucket.start_of(&utc(2019, 5, 15, 14, 45)),
+            utc(2019, 5, 15, 14, 45)
+        );
+        assert_eq!(
+            Bucket::Fixed(Duration::hours(1)).start_of(&utc(1969, 12, 31, 23, 30)),
+            utc(1969, 12, 31, 23, 0)
+        );
+        assert_eq!(
+            bucket.bounds_of(&utc(2019, 5, 15, 14, 44)),
+            (utc(2019, 5, 15, 14, 30), utc(2019, 5, 15, 14, 45))
+        );
+        assert_eq!(
+            Bucket::Fixed(Duration::zero()).bounds_of(&utc(2019, 5, 15, 14, 44)),
+            (utc(2019, 5, 15, 14, 44), utc(2019, 5, 15, 14, 44))
+        );
+    }
+
+    #[test]
+    fn it_finds_calendar_bucket_starts() {
+        let time = utc(2019, 5, 15, 14, 30);
+        assert_eq!(
+            Bucket::Calendar(CalendarUnit::Day).start_of(&time),
+            utc(2019, 5, 15, 0, 0)
+        );
+        assert_eq!(
+            Bucket::Calendar(CalendarUnit::Week).start_of(&time),
+            utc(2019, 5, 13, 0, 0)
+        );
+        assert_eq!(
+            Bucket::Calendar(CalendarUnit::Month).start_of(&time),
+            utc(2019, 5, 1, 0, 0)
+        );
+        assert_eq!(
+            Bucket::Calendar(CalendarUnit::Year).start_of(&time),
+            utc(2019, 1, 1, 0, 0)
+        );
+    }
+
+    #[test]
+    fn it_buckets_in_the_local_calendar() {
+        let late_evening = DateTimeTz(Central.with_ymd_and_hms(2019, 5, 15, 23, 0, 0).unwrap());
+        let day = Bucket::Calendar(CalendarUnit::Day).start_of(&late_evening);
+        assert_eq!(
+            day,
+            DateTimeTz(Central.with_ymd_and_hms(2019, 5, 15, 0, 0, 0).unwrap())
+        );
+        assert_eq!(day.0.timezone(), Central);
+        assert_eq!(
+            Bucket::CalendarIn(CalendarUnit::Day, UTC).start_of(&late_evening),
+            utc(2019, 5, 16, 0, 0)
+        );
+        assert_eq!(
+            Bucket::CalendarIn(CalendarUnit::Quarter, Central).start_of(&late_evening),
+            DateTimeTz(Central.with_ymd_and_hms(2019, 4, 1, 0, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn it_handles_daylight_saving_transitions() {
+        let spring = DateTimeTz(Central.with_ymd_and_hms(2019, 3, 10, 12, 0, 0).unwrap());
+        let (start, end) = CalendarUnit::Day.bounds_in(&spring, Central);
+        assert_eq!(end.0 - start.0, Duration::hours(23));
+
+        let fall = DateTimeTz(Central.with_ymd_and_hms(2019, 11, 3, 12, 0, 0).unwrap());
+        let (start, end) = CalendarUnit::Day.bounds_in(&fall, Central);
+        assert_eq!(end.0 - start.0, Duration::hours(25));
+
+        let (start, end) = CalendarUnit::Month.bounds_in(&fall, Central);
+        assert_eq!(
+            start,
+            DateTimeTz(Central.with_ymd_and_hms(2019, 11, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(
+            end,
+            DateTimeTz(Central.with_ymd_and_hms(2019, 12, 1, 0, 0, 0).unwrap())
+        );
+
+        // Samoa skipped the whole of 2011-12-30 when it moved across the date line.
+        let before = DateTimeTz(Apia.with_ymd_and_hms(2011, 12, 29, 12, 0, 0).unwrap());
+        let (start, end) = CalendarUnit::Day.bounds_in(&before, Apia);
+        assert_eq!(end.0 - start.0, Duration::hours(24));
+        assert_eq!(
+            end,
+            DateTimeTz(Apia.with_ymd_and_hms(2011, 12, 31, 0, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn it_summarizes_buckets() {
+        let values = vec![
+            (utc(2019, 5, 15, 1, 0), 3.0),
+            (utc(2019, 5, 15, 2, 0), 1.0),
+            (utc(2019, 5, 15, 3, 0), 2.0),
+            (utc(2019, 5, 17, 1, 0), 5.0),
+        ];
+        let buckets = summarize(&Bucket::Calendar(CalendarUnit::Day), values);
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].0, utc(2019, 5, 15, 0, 0));
+        assert_eq!(buckets[0].1.reduce(Reducer::Count), 3.0);
+        assert_eq!(buckets[0].1.reduce(Reducer::Sum), 6.0);
+        assert_eq!(buckets[0].1.reduce(Reducer::Min), 1.0);
+        assert_eq!(buckets[0].1.reduce(Reducer::Max), 3.0);
+        assert_eq!(buckets[0].1.reduce(Reducer::Mean), 2.0);
+        assert_eq!(buckets[0].1.reduce(Reducer::First), 3.0);
+        assert_eq!(buckets[0].1.reduce(Reducer::Last), 2.0);
+        assert_eq!(buckets[1].0, utc(2019, 5, 17, 0, 0));
+        assert_eq!(buckets[1].1.reduce(Reducer::Sum), 5.0);
+    }
+}
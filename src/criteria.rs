@@ -1,11 +1,132 @@
+use crate::aggregate::CalendarUnit;
 use crate::date_time_tz::DateTimeTz;
+use crate::field::FieldCriteria;
 use crate::types::Recordable;
+use chrono_tz::Tz;
+use serde::ser::Serialize;
+use std::ops::Bound;
 
 /// This trait is used for constructing queries for searching the database.
 pub trait Criteria {
     /// Apply this criteria element to a record, returning true only if the record matches the
-    /// criteria.
-    fn apply<T: Recordable>(&self, record: &T) -> bool;
+    /// criteria. The record is serializable so that criteria can inspect the record data as well
+    /// as the timestamp and tags.
+    fn apply<T: Recordable + Serialize>(&self, record: &T) -> bool;
+
+    /// The range of timestamps that a record must fall into in order to match this criteria. The
+    /// series uses this to walk only the matching part of its time index, and then applies the
+    /// full criteria to each record found there. The default places no bounds on the time.
+    fn time_bounds(&self) -> TimeBounds {
+        TimeBounds::unbounded()
+    }
+
+    /// Tags that a record must carry in order to match this criteria. The series uses this to
+    /// look up candidate records in its tag index before applying the full criteria. The default
+    /// requires no tags.
+    fn required_tags(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// A range of timestamps, each end of which may be inclusive, exclusive, or unbounded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeBounds {
+    pub start: Bound<DateTimeTz>,
+    pub end: Bound<DateTimeTz>,
+}
+
+impl TimeBounds {
+    /// A range that includes all times.
+    pub fn unbounded() -> TimeBounds {
+        TimeBounds {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+
+    /// The range of times that fall into both this range and the other one.
+    pub fn intersect(self, other: TimeBounds) -> TimeBounds {
+        let start = match (self.start, other.start) {
+            (Bound::Unbounded, b) | (b, Bound::Unbounded) => b,
+            (Bound::Included(l), Bound::Included(r)) => Bound::Included(l.max(r)),
+            (Bound::Excluded(l), Bound::Excluded(r)) => Bound::Excluded(l.max(r)),
+            (Bound::Included(i), Bound::Excluded(e)) | (Bound::Excluded(e), Bound::Included(i)) => {
+                if i > e {
+                    Bound::Included(i)
+                } else {
+                    Bound::Excluded(e)
+                }
+            }
+        };
+        let end = match (self.end, other.end) {
+            (Bound::Unbounded, b) | (b, Bound::Unbounded) => b,
+            (Bound::Included(l), Bound::Included(r)) => Bound::Included(l.min(r)),
+            (Bound::Excluded(l), Bound::Excluded(r)) => Bound::Excluded(l.min(r)),
+            (Bound::Included(i), Bound::Excluded(e)) | (Bound::Excluded(e), Bound::Included(i)) => {
+                if i < e {
+                    Bound::Included(i)
+                } else {
+                    Bound::Excluded(e)
+                }
+            }
+        };
+        TimeBounds { start, end }
+    }
+
+    /// The smallest range that includes every time in either this range or the other one.
+    pub fn hull(self, other: TimeBounds) -> TimeBounds {
+        let start = match (self.start, other.start) {
+            (Bound::Unbounded, _) | (_, Bound::Unbounded) => Bound::Unbounded,
+            (Bound::Included(l), Bound::Included(r)) => Bound::Included(l.min(r)),
+            (Bound::Excluded(l), Bound::Excluded(r)) => Bound::Excluded(l.min(r)),
+            (Bound::Included(i), Bound::Excluded(e)) | (Bound::Excluded(e), Bound::Included(i)) => {
+                if i <= e {
+                    Bound::Included(i)
+                } else {
+                    Bound::Excluded(e)
+                }
+            }
+        };
+        let end = match (self.end, other.end) {
+            (Bound::Unbounded, _) | (_, Bound::Unbounded) => Bound::Unbounded,
+            (Bound::Included(l), Bound::Included(r)) => Bound::Included(l.max(r)),
+            (Bound::Excluded(l), Bound::Excluded(r)) => Bound::Excluded(l.max(r)),
+            (Bound::Included(i), Bound::Excluded(e)) | (Bound::Excluded(e), Bound::Included(i)) => {
+                if i >= e {
+                    Bound::Included(i)
+                } else {
+                    Bound::Excluded(e)
+                }
+            }
+        };
+        TimeBounds { start, end }
+    }
+
+    /// True if the time falls into this range.
+    pub fn contains(&self, time: &DateTimeTz) -> bool {
+        let after_start = match &self.start {
+            Bound::Included(start) => time >= start,
+            Bound::Excluded(start) => time > start,
+            Bound::Unbounded => true,
+        };
+        let before_end = match &self.end {
+            Bound::Included(end) => time <= end,
+            Bound::Excluded(end) => time < end,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    /// True if no time can fall into this range.
+    pub fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s), Bound::Excluded(e))
+            | (Bound::Excluded(s), Bound::Included(e))
+            | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            _ => false,
+        }
+    }
 }
 
 /// Specify two criteria that must both be matched.
@@ -19,9 +140,19 @@ where
     A: Criteria,
     B: Criteria,
 {
-    fn apply<T: Recordable>(&self, record: &T) -> bool {
+    fn apply<T: Recordable + Serialize>(&self, record: &T) -> bool {
         self.lside.apply(record) && self.rside.apply(record)
     }
+
+    fn time_bounds(&self) -> TimeBounds {
+        self.lside.time_bounds().intersect(self.rside.time_bounds())
+    }
+
+    fn required_tags(&self) -> Vec<String> {
+        let mut tags = self.lside.required_tags();
+        tags.extend(self.rside.required_tags());
+        tags
+    }
 }
 
 /// Specify two criteria, either of which may be matched.
@@ -30,50 +161,227 @@ pub struct Or<A: Criteria, B: Criteria> {
     pub rside: B,
 }
 
+impl<A, B> Criteria for Or<A, B>
+where
+    A: Criteria,
+    B: Criteria,
+{
+    fn apply<T: Recordable + Serialize>(&self, record: &T) -> bool {
+        self.lside.apply(record) || self.rside.apply(record)
+    }
+
+    fn time_bounds(&self) -> TimeBounds {
+        self.lside.time_bounds().hull(self.rside.time_bounds())
+    }
+
+    fn required_tags(&self) -> Vec<String> {
+        let rside_tags = self.rside.required_tags();
+        self.lside
+            .required_tags()
+            .into_iter()
+            .filter(|tag| rside_tags.contains(tag))
+            .collect()
+    }
+}
+
+/// Specify a criteria that must not be matched.
+pub struct Not<C: Criteria> {
+    pub criteria: C,
+}
+
+impl<C> Criteria for Not<C>
+where
+    C: Criteria,
+{
+    fn apply<T: Recordable + Serialize>(&self, record: &T) -> bool {
+        !self.criteria.apply(record)
+    }
+}
+
+/// Builder methods for composing criteria without nesting the structures by hand. This is
+/// implemented for every `Criteria`, so a query such as "in this week, tagged either a or b, but
+/// not tagged c" can be written as:
+///
+/// ```text
+/// time_range(week_start, true, week_end, false)
+///     .and(Tags { tags: vec![a] }.or(Tags { tags: vec![b] }))
+///     .and(Tags { tags: vec![c] }.not())
+/// ```
+pub trait CriteriaExt: Criteria + Sized {
+    /// Require that both this criteria and the other one match.
+    fn and<C: Criteria>(self, other: C) -> And<Self, C> {
+        And {
+            lside: self,
+            rside: other,
+        }
+    }
+
+    /// Require that either this criteria or the other one match.
+    fn or<C: Criteria>(self, other: C) -> Or<Self, C> {
+        Or {
+            lside: self,
+            rside: other,
+        }
+    }
+
+    /// Require that this criteria does not match.
+    fn not(self) -> Not<Self> {
+        Not { criteria: self }
+    }
+}
+
+impl<C: Criteria> CriteriaExt for C {}
+
 /// Specify the starting time for a search. This consists of a UTC timestamp and a specifier as to
 /// whether the exact time is included in the search criteria.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct StartTime {
     pub time: DateTimeTz,
     pub incl: bool,
 }
 
 impl Criteria for StartTime {
-    fn apply<T: Recordable>(&self, record: &T) -> bool {
+    fn apply<T: Recordable + Serialize>(&self, record: &T) -> bool {
         if self.incl {
             record.timestamp() >= self.time
         } else {
             record.timestamp() > self.time
         }
     }
+
+    fn time_bounds(&self) -> TimeBounds {
+        TimeBounds {
+            start: if self.incl {
+                Bound::Included(self.time.clone())
+            } else {
+                Bound::Excluded(self.time.clone())
+            },
+            end: Bound::Unbounded,
+        }
+    }
 }
 
 /// Specify the ending time for a search. This consists of a UTC timestamp and a specifier as to
 /// whether the exact time is included in the search criteria.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct EndTime {
     pub time: DateTimeTz,
     pub incl: bool,
 }
 
 impl Criteria for EndTime {
-    fn apply<T: Recordable>(&self, record: &T) -> bool {
+    fn apply<T: Recordable + Serialize>(&self, record: &T) -> bool {
         if self.incl {
             record.timestamp() <= self.time
         } else {
             record.timestamp() < self.time
         }
     }
+
+    fn time_bounds(&self) -> TimeBounds {
+        TimeBounds {
+            start: Bound::Unbounded,
+            end: if self.incl {
+                Bound::Included(self.time.clone())
+            } else {
+                Bound::Excluded(self.time.clone())
+            },
+        }
+    }
 }
 
 /// Specify a list of tags that must exist on the record.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Tags {
     pub tags: Vec<String>,
 }
 
 impl Criteria for Tags {
-    fn apply<T: Recordable>(&self, record: &T) -> bool {
+    fn apply<T: Recordable + Serialize>(&self, record: &T) -> bool {
         let record_tags = record.tags();
         self.tags.iter().all(|v| record_tags.contains(v))
     }
+
+    fn required_tags(&self) -> Vec<String> {
+        self.tags.clone()
+    }
+}
+
+/// A criteria tree that can be assembled at runtime, such as from user input, and serialized.
+///
+/// The generic `And`, `Or`, and `Not` combinators fix the shape of a query at compile time. This
+/// enum covers the same ground, but each node is a value, so the shape of the tree is free to
+/// vary. It is a `Criteria` itself and can be passed directly to `Series::search`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum DynCriteria {
+    /// Every one of the criteria must match. An empty list matches every record.
+    AllOf(Vec<DynCriteria>),
+
+    /// At least one of the criteria must match. An empty list matches no records.
+    AnyOf(Vec<DynCriteria>),
+
+    /// The criteria must not match.
+    Not(Box<DynCriteria>),
+
+    StartTime(StartTime),
+
+    EndTime(EndTime),
+
+    Tags(Tags),
+
+    Field(FieldCriteria),
+}
+
+impl Criteria for DynCriteria {
+    fn apply<T: Recordable + Serialize>(&self, record: &T) -> bool {
+        match self {
+            DynCriteria::AllOf(criteria) => criteria.iter().all(|c| c.apply(record)),
+            DynCriteria::AnyOf(criteria) => criteria.iter().any(|c| c.apply(record)),
+            DynCriteria::Not(criteria) => !criteria.apply(record),
+            DynCriteria::StartTime(criteria) => criteria.apply(record),
+            DynCriteria::EndTime(criteria) => criteria.apply(record),
+            DynCriteria::Tags(criteria) => criteria.apply(record),
+            DynCriteria::Field(criteria) => criteria.apply(record),
+        }
+    }
+
+    fn time_bounds(&self) -> TimeBounds {
+        match self {
+            DynCriteria::AllOf(criteria) => {
+                criteria.iter().fold(TimeBounds::unbounded(), |acc, c| {
+                    acc.intersect(c.time_bounds())
+                })
+            }
+            DynCriteria::AnyOf(criteria) => criteria
+                .iter()
+                .map(|c| c.time_bounds())
+                .reduce(|acc, bounds| acc.hull(bounds))
+                .unwrap_or_else(TimeBounds::unbounded),
+            DynCriteria::Not(_) => TimeBounds::unbounded(),
+            DynCriteria::StartTime(criteria) => criteria.time_bounds(),
+            DynCriteria::EndTime(criteria) => criteria.time_bounds(),
+            DynCriteria::Tags(criteria) => criteria.time_bounds(),
+            DynCriteria::Field(criteria) => criteria.time_bounds(),
+        }
+    }
+
+    fn required_tags(&self) -> Vec<String> {
+        match self {
+            DynCriteria::AllOf(criteria) => {
+                criteria.iter().flat_map(|c| c.required_tags()).collect()
+            }
+            DynCriteria::AnyOf(criteria) => criteria
+                .iter()
+                .map(|c| c.required_tags())
+                .reduce(|acc, tags| acc.into_iter().filter(|tag| tags.contains(tag)).collect())
+                .unwrap_or_default(),
+            DynCriteria::Not(_) => Vec::new(),
+            DynCriteria::StartTime(criteria) => criteria.required_tags(),
+            DynCriteria::EndTime(criteria) => criteria.required_tags(),
+            DynCriteria::Tags(criteria) => criteria.required_tags(),
+            DynCriteria::Field(criteria) => criteria.required_tags(),
+        }
+    }
 }
 
 /// Specify a criteria that searches for records matching an exact time.
@@ -105,3 +413,11 @@ pub fn time_range(
         },
     }
 }
+
+/// Specify a criteria that searches for all records within the calendar unit, as observed in the
+/// time zone `tz`, that contains `time`. For instance, `calendar_range(CalendarUnit::Day, now,
+/// US::Central)` finds everything recorded on the current local day in Chicago.
+pub fn calendar_range(unit: CalendarUnit, time: &DateTimeTz, tz: Tz) -> And<StartTime, EndTime> {
+    let (start, end) = unit.bounds_in(time, tz);
+    time_range(start, true, end, false)
+}
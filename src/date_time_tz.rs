@@ -22,6 +22,11 @@ use std::fmt;
 pub struct DateTimeTz(pub chrono::DateTime<chrono_tz::Tz>);
 
 impl DateTimeTz {
+    /// The current time, in UTC.
+    pub fn now() -> DateTimeTz {
+        DateTimeTz(chrono::Utc::now().with_timezone(&UTC))
+    }
+
     pub fn map<F>(&self, f: F) -> DateTimeTz
     where
         F: FnOnce(chrono::DateTime<chrono_tz::Tz>) -> chrono::DateTime<chrono_tz::Tz>,
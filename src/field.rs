@@ -0,0 +1,221 @@
+extern crate regex;
+extern crate serde_json;
+
+use self::regex::Regex;
+use self::serde_json::Value;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::criteria::Criteria;
+use crate::types::{Error, Recordable};
+
+/// Specify a predicate over a single field of the record data.
+///
+/// The record is serialized to JSON and `path` is evaluated against it as a JSON pointer (RFC
+/// 6901), so `/distance` selects the `distance` field and `/laps/0/time` selects the `time` of the
+/// first lap. A record with no value at `path` does not match, whatever the operation.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FieldCriteria {
+    pub path: String,
+    pub op: FieldOp,
+}
+
+/// The comparison to apply to the value found at a `FieldCriteria` path.
+///
+/// Numbers are compared numerically, so `77` equals `77.0`. Strings are compared
+/// lexicographically. Values of different types are never equal and never ordered.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum FieldOp {
+    Eq(Value),
+    Lt(Value),
+    Le(Value),
+    Gt(Value),
+    Ge(Value),
+
+    /// The value must equal one of the listed values.
+    In(Vec<Value>),
+
+    /// The path must be present in the record, with any value.
+    Exists,
+
+    /// The value must be a string matching the regular expression.
+    Matches(Pattern),
+}
+
+impl Criteria for FieldCriteria {
+    fn apply<T: Recordable + Serialize>(&self, record: &T) -> bool {
+        let value = match serde_json::to_value(record) {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        let field = match value.pointer(&self.path) {
+            Some(field) => field,
+            None => return false,
+        };
+        match &self.op {
+            FieldOp::Eq(v) => compare(field, v) == Some(Ordering::Equal),
+            FieldOp::Lt(v) => compare(field, v) == Some(Ordering::Less),
+            FieldOp::Le(v) => matches!(
+                compare(field, v),
+                Some(Ordering::Less) | Some(Ordering::Equal)
+            ),
+            FieldOp::Gt(v) => compare(field, v) == Some(Ordering::Greater),
+            FieldOp::Ge(v) => matches!(
+                compare(field, v),
+                Some(Ordering::Greater) | Some(Ordering::Equal)
+            ),
+            FieldOp::In(vs) => vs
+                .iter()
+                .any(|v| compare(field, v) == Some(Ordering::Equal)),
+            FieldOp::Exists => true,
+            FieldOp::Matches(pattern) => match field {
+                Value::String(s) => pattern.0.is_match(s),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Compare two JSON values, returning None if they are not of comparable types.
+fn compare(l: &Value, r: &Value) -> Option<Ordering> {
+    match (l, r) {
+        (Value::Number(l), Value::Number(r)) => l.as_f64()?.partial_cmp(&r.as_f64()?),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+        (l, r) if l == r => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+/// A compiled regular expression, which serializes as its source string.
+#[derive(Clone, Debug)]
+pub struct Pattern(pub Regex);
+
+impl Pattern {
+    /// Compile a regular expression. Raise RegexParseError if the expression is invalid.
+    pub fn new(re: &str) -> Result<Pattern, Error> {
+        Regex::new(re).map(Pattern).map_err(Error::RegexParseError)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+struct PatternVisitor;
+
+impl<'de> Visitor<'de> for PatternVisitor {
+    type Value = Pattern;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string containing a regular expression")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        Regex::new(s).map(Pattern).map_err(E::custom)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(PatternVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate serde_json;
+
+    use super::{FieldCriteria, FieldOp, Pattern};
+    use crate::criteria::Criteria;
+    use crate::date_time_tz::DateTimeTz;
+    use crate::types::Recordable;
+    use chrono::TimeZone;
+    use chrono_tz::Etc::UTC;
+
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    struct Run {
+        date: DateTimeTz,
+        distance: f64,
+        route: String,
+        shoes: Option<String>,
+    }
+
+    impl Recordable for Run {
+        fn timestamp(&self) -> DateTimeTz {
+            self.date.clone()
+        }
+
+        fn tags(&self) -> Vec<String> {
+            Vec::new()
+        }
+    }
+
+    fn mk_run() -> Run {
+        Run {
+            date: DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 1, 0, 0, 0).unwrap()),
+            distance: 5000.0,
+            route: String::from("river loop"),
+            shoes: None,
+        }
+    }
+
+    fn field(path: &str, op: FieldOp) -> FieldCriteria {
+        FieldCriteria {
+            path: String::from(path),
+            op,
+        }
+    }
+
+    #[test]
+    fn it_compares_numbers() {
+        let run = mk_run();
+        assert!(field("/distance", FieldOp::Eq(serde_json::json!(5000))).apply(&run));
+        assert!(field("/distance", FieldOp::Gt(serde_json::json!(4000.5))).apply(&run));
+        assert!(field("/distance", FieldOp::Ge(serde_json::json!(5000))).apply(&run));
+        assert!(!field("/distance", FieldOp::Lt(serde_json::json!(5000))).apply(&run));
+        assert!(!field("/distance", FieldOp::Eq(serde_json::json!("5000"))).apply(&run));
+        assert!(field(
+            "/distance",
+            FieldOp::In(vec![serde_json::json!(1000), serde_json::json!(5000)])
+        )
+        .apply(&run));
+    }
+
+    #[test]
+    fn it_checks_existence_and_patterns() {
+        let run = mk_run();
+        assert!(field("/shoes", FieldOp::Exists).apply(&run));
+        assert!(!field("/weather", FieldOp::Exists).apply(&run));
+        assert!(!field("/weather", FieldOp::Eq(serde_json::json!(null))).apply(&run));
+        assert!(field("/route", FieldOp::Matches(Pattern::new("^river").unwrap())).apply(&run));
+        assert!(!field("/route", FieldOp::Matches(Pattern::new("^lake").unwrap())).apply(&run));
+        assert!(!field("/distance", FieldOp::Matches(Pattern::new(".*").unwrap())).apply(&run));
+    }
+
+    #[test]
+    fn it_serializes() {
+        let criteria = field("/route", FieldOp::Matches(Pattern::new("loop$").unwrap()));
+        let as_json = serde_json::to_string(&criteria).unwrap();
+        assert_eq!(
+            as_json,
+            "{\"path\":\"/route\",\"op\":{\"Matches\":\"loop$\"}}"
+        );
+        let parsed: FieldCriteria = serde_json::from_str(&as_json).unwrap();
+        assert_eq!(parsed, criteria);
+        assert!(serde_json::from_str::<FieldCriteria>(
+            "{\"path\":\"/route\",\"op\":{\"Matches\":\"(\"}}"
+        )
+        .is_err());
+    }
+}
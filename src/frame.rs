@@ -0,0 +1,65 @@
+extern crate crc32fast;
+
+use crate::types::Error;
+
+/// The length of the checksum prefix on a framed line: eight hex digits and a space.
+const PREFIX_LEN: usize = 9;
+
+/// Frame a serialized entry for the series file. With `checksum` set, the entry is prefixed with
+/// the CRC32 of the entry as eight lowercase hex digits and a space. Otherwise the entry is
+/// written bare, as in legacy files.
+pub fn encode(payload: &str, checksum: bool) -> String {
+    if checksum {
+        format!("{:08x} {}", crc32fast::hash(payload.as_bytes()), payload)
+    } else {
+        String::from(payload)
+    }
+}
+
+/// Unframe a line from the series file, verifying its checksum if it has one. `line_no` is the
+/// 1-based line number, used to report a mismatch.
+///
+/// A bare entry always starts with `{`, so any line with a checksum prefix is unambiguous. A line
+/// that has neither is returned as it is, and fails when it is parsed.
+pub fn decode(line: &str, line_no: usize) -> Result<&str, Error> {
+    let (prefix, payload) = match line.as_bytes().get(PREFIX_LEN - 1) {
+        Some(b' ') => line.split_at(PREFIX_LEN),
+        _ => return Ok(line),
+    };
+    let expected = match u32::from_str_radix(&prefix[..PREFIX_LEN - 1], 16) {
+        Ok(expected) => expected,
+        Err(_) => return Ok(line),
+    };
+    if crc32fast::hash(payload.as_bytes()) == expected {
+        Ok(payload)
+    } else {
+        Err(Error::ChecksumMismatch { line: line_no })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode};
+    use crate::types::Error;
+
+    #[test]
+    fn it_round_trips() {
+        let payload = "{\"id\":\"3330c5b0-783f-4919-b2c4-8169c38f65ff\",\"data\":null}";
+        assert_eq!(encode(payload, false), payload);
+        let framed = encode(payload, true);
+        assert_eq!(framed.len(), payload.len() + 9);
+        assert_eq!(decode(&framed, 1).unwrap(), payload);
+        assert_eq!(decode(payload, 1).unwrap(), payload);
+    }
+
+    #[test]
+    fn it_detects_corruption() {
+        let framed = encode("{\"id\":\"a\",\"data\":{\"depth\":4.5}}", true);
+        let corrupt = framed.replace("4.5", "4.6");
+        match decode(&corrupt, 7) {
+            Err(Error::ChecksumMismatch { line: 7 }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(decode("garbage", 1).unwrap(), "garbage");
+    }
+}
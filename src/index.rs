@@ -0,0 +1,104 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::criteria::TimeBounds;
+use crate::date_time_tz::DateTimeTz;
+use crate::types::UniqueId;
+
+/// A secondary index from record timestamps to record ids. Searches over a time range walk only
+/// the part of the index that falls into the range, and get the ids back in time order.
+///
+/// Records that share a timestamp are ordered by their ids, so that the ordering is stable.
+#[derive(Default)]
+pub struct TimeIndex {
+    times: BTreeMap<DateTimeTz, BTreeSet<UniqueId>>,
+}
+
+impl TimeIndex {
+    pub fn new() -> TimeIndex {
+        TimeIndex::default()
+    }
+
+    /// Add a record to the index.
+    pub fn insert(&mut self, time: DateTimeTz, id: UniqueId) {
+        self.times.entry(time).or_default().insert(id);
+    }
+
+    /// Remove a record from the index. `time` must be the timestamp that the record was indexed
+    /// under.
+    pub fn remove(&mut self, time: &DateTimeTz, id: &UniqueId) {
+        if let Some(ids) = self.times.get_mut(time) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.times.remove(time);
+            }
+        }
+    }
+
+    /// Iterate, in time order, over the ids of all records with timestamps inside of `bounds`.
+    pub fn range<'s>(
+        &'s self,
+        bounds: &TimeBounds,
+    ) -> Box<dyn DoubleEndedIterator<Item = &'s UniqueId> + 's> {
+        if bounds.is_empty() {
+            return Box::new(std::iter::empty());
+        }
+        Box::new(
+            self.times
+                .range((bounds.start.clone(), bounds.end.clone()))
+                .flat_map(|(_, ids)| ids.iter()),
+        )
+    }
+}
+
+/// An inverted index from tags to the ids of the records that carry them.
+#[derive(Default)]
+pub struct TagIndex {
+    tags: HashMap<String, HashSet<UniqueId>>,
+}
+
+impl TagIndex {
+    pub fn new() -> TagIndex {
+        TagIndex::default()
+    }
+
+    /// Add a record to the index under each of its tags.
+    pub fn insert(&mut self, tags: Vec<String>, id: &UniqueId) {
+        for tag in tags {
+            self.tags.entry(tag).or_default().insert(id.clone());
+        }
+    }
+
+    /// Remove a record from the index. `tags` must be the tags that the record was indexed under.
+    pub fn remove(&mut self, tags: Vec<String>, id: &UniqueId) {
+        for tag in tags {
+            if let Some(ids) = self.tags.get_mut(&tag) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.tags.remove(&tag);
+                }
+            }
+        }
+    }
+
+    /// Find the ids of all records that carry every one of the tags. The posting lists are
+    /// intersected starting from the shortest one.
+    pub fn intersect(&self, tags: &[String]) -> HashSet<UniqueId> {
+        let mut postings = Vec::with_capacity(tags.len());
+        for tag in tags {
+            match self.tags.get(tag) {
+                Some(ids) => postings.push(ids),
+                None => return HashSet::new(),
+            }
+        }
+        postings.sort_by_key(|ids| ids.len());
+
+        match postings.split_first() {
+            None => HashSet::new(),
+            Some((shortest, rest)) => shortest
+                .iter()
+                .filter(|id| rest.iter().all(|ids| ids.contains(*id)))
+                .cloned()
+                .collect(),
+        }
+    }
+}
@@ -32,7 +32,9 @@ impl Recordable for BikeTrip {
 ```
 
 Recordable requires implementations for `timestamp` and `tags`, both of which can be used for
-searching for records, and both of which may be used for indexing in the future.
+searching for records. Records are indexed by both. Searches with `StartTime`, `EndTime`,
+`exact_time`, or `time_range` criteria only visit the records inside of that time range, and
+searches with `Tags` criteria only visit the records that carry all of those tags.
 
 The series can only store a single data type, but you can always store multiple data types by
 wrapping them into a single enum.
@@ -46,7 +48,34 @@ let mut ts: Series<BikeTrip> = Series::open("var/bike_trips.json")
 
 The series file will be created if it does not already exist. If it does already exist, the existing data will be read into memory and made available.
 
-Note: all of the data is read into memory at once. For human-scale things, this probably takes up very little memory, but this software is not optimized for IoT scale deployments. Additionally, this library assumes only one process is writing to the file. Behavior from more than one process writing to the file is currently undefined.
+Criteria can also be parsed from a textual query into a `DynCriteria`:
+
+```text
+let criteria: DynCriteria =
+    "time >= 2019-05-01T00:00:00Z US/Central and tags has \"run\" and not tags has \"treadmill\""
+        .parse()?;
+let runs = ts.search(criteria)?;
+```
+
+Every update and delete appends a new line to the file, so a long-lived series file accumulates
+dead lines. `Series::compact` rewrites the file with only the live records, and
+`Series::open_with_options` accepts a `Compaction` policy to do this automatically.
+
+Note: all of the data is read into memory at once. For human-scale things, this probably takes up very little memory, but this software is not optimized for IoT scale deployments.
+
+Opening a series takes an advisory lock on the file, exclusive for writers and shared for
+`Access::ReadOnly` opens, so a second process that tries to write to the same file gets
+`Error::Locked` instead of interleaving its writes. The lock is advisory, so it only protects
+against other processes that also use this library with locking enabled.
+
+A process that only needs to watch a series that another process writes can use
+`Series::open_read_only`, which takes no lock, and call `refresh` to pick up new entries as they
+are appended.
+
+When a record type gains a field, entries written before the change no longer deserialize.
+Register a chain of upgrade functions with `Migrations` in `SeriesOptions` and they are applied
+to the JSON of older entries as the file is loaded; `rewrite_migrated` rewrites the file in the
+newest version.
 */
 
 #[macro_use]
@@ -55,12 +84,30 @@ extern crate chrono;
 extern crate chrono_tz;
 extern crate serde;
 
+mod aggregate;
 mod criteria;
 mod date_time_tz;
+mod field;
+mod frame;
+mod index;
+mod options;
+mod query;
+mod rollup;
 mod series;
+mod transaction;
 mod types;
 
+pub use aggregate::{Bucket, CalendarUnit, Reducer, Summary};
 pub use criteria::*;
 pub use date_time_tz::DateTimeTz;
+pub use field::{FieldCriteria, FieldOp, Pattern};
+pub use options::{
+    Access, Compaction, Cursor, Durability, Migrations, Order, QueryOptions, SeriesOptions,
+    TornWrite,
+};
+pub use rollup::{Rollup, RollupRecord};
 pub use series::Series;
-pub use types::{Error, Record, Recordable, UniqueId};
+pub use transaction::Transaction;
+pub use types::{
+    Error, Header, Metadata, Record, Recordable, RepairReport, UniqueId, Version, FORMAT_VERSION,
+};
@@ -0,0 +1,254 @@
+extern crate chrono;
+extern crate serde_json;
+
+use self::chrono::Duration;
+use self::serde_json::Value;
+
+use crate::date_time_tz::DateTimeTz;
+use crate::types::{Error, UniqueId};
+
+/// Options that control how a series file is opened and maintained.
+///
+/// `SeriesOptions::default()` gives the same behavior as `Series::open`. Individual options can be
+/// overridden with struct update syntax:
+///
+/// ```text
+/// let options = SeriesOptions {
+///     compaction: Compaction::DeadRatio { ratio: 0.5, min_lines: 1000 },
+///     ..SeriesOptions::default()
+/// };
+/// let ts: Series<BikeTrip> = Series::open_with_options("var/bike_trips.json", options)?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct SeriesOptions {
+    /// When the series file should be compacted automatically.
+    pub compaction: Compaction,
+
+    /// Only keep records with timestamps within this long before the present. Older records are
+    /// dropped from memory when the series is opened or refreshed and after every write, and
+    /// dropped from the file when it is compacted.
+    pub retention: Option<Duration>,
+
+    /// Whether the series may be written to.
+    pub access: Access,
+
+    /// Whether to take an advisory lock on the series file, so that other processes that also
+    /// lock the file cannot write to it at the same time. Writers take an exclusive lock, and
+    /// read-only opens take a shared lock.
+    pub lock: bool,
+
+    /// What to do when the final line of the file cannot be parsed.
+    pub torn_write: TornWrite,
+
+    /// Whether to write every entry with a CRC32 checksum of its contents. Checksums are always
+    /// verified when a file is read, whatever this setting, and files can mix lines with and
+    /// without checksums, so this can be turned on for an existing file. A file whose header says
+    /// that it was created with checksums is always written with them, whatever this setting.
+    pub checksums: bool,
+
+    /// When writes are synced to disk.
+    pub durability: Durability,
+
+    /// A name for the author or process writing the series, recorded with every write.
+    pub source: Option<String>,
+
+    /// Whether to start a new file with a header line that records the format version, the record
+    /// type and the options the file was created with. A header is read and checked whenever one
+    /// is present, whatever this setting.
+    pub header: bool,
+
+    /// Upgrades for entries written with older versions of the record type.
+    pub migrations: Migrations,
+
+    /// Whether to compact the file when it is opened if any live record was written with an older
+    /// version of the record type, so that the whole file is in the newest version. Like any
+    /// compaction, this discards the history of updated and deleted records.
+    pub rewrite_migrated: bool,
+}
+
+impl Default for SeriesOptions {
+    fn default() -> SeriesOptions {
+        SeriesOptions {
+            compaction: Compaction::Manual,
+            retention: None,
+            access: Access::ReadWrite,
+            lock: true,
+            torn_write: TornWrite::Fail,
+            checksums: false,
+            durability: Durability::Never,
+            source: None,
+            header: false,
+            migrations: Migrations::default(),
+            rewrite_migrated: false,
+        }
+    }
+}
+
+/// A chain of upgrade functions for the record type, applied to the JSON of each entry's data as
+/// it is loaded, before it is deserialized.
+///
+/// Record type versions are numbered from 0, which is the version of every entry written before
+/// any migration was registered. The first function registered upgrades data from version 0 to
+/// version 1, the second from version 1 to version 2, and so on, so the current version is the
+/// number of functions registered. New entries are written with the current version.
+///
+/// ```text
+/// fn add_notes(mut data: Value) -> Value {
+///     data["notes"] = Value::String(String::new());
+///     data
+/// }
+///
+/// let options = SeriesOptions {
+///     migrations: Migrations::new().then(add_notes),
+///     ..SeriesOptions::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Migrations {
+    steps: Vec<fn(Value) -> Value>,
+}
+
+impl Migrations {
+    pub fn new() -> Migrations {
+        Migrations::default()
+    }
+
+    /// Register the upgrade from the current version to the next one.
+    pub fn then(mut self, step: fn(Value) -> Value) -> Migrations {
+        self.steps.push(step);
+        self
+    }
+
+    /// The current version of the record type.
+    pub fn version(&self) -> u32 {
+        self.steps.len() as u32
+    }
+
+    /// Upgrade data written with version `from` to the current version. Raise UnsupportedFormat if
+    /// `from` is newer than the current version.
+    pub fn upgrade(&self, data: Value, from: u32) -> Result<Value, Error> {
+        if from > self.version() {
+            return Err(Error::UnsupportedFormat(format!(
+                "record version {} is newer than the current version {}",
+                from,
+                self.version()
+            )));
+        }
+        Ok(self.steps[from as usize..]
+            .iter()
+            .fold(data, |data, step| step(data)))
+    }
+}
+
+/// How a series file is opened.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// Open the file for reading and appending, creating it if it does not already exist.
+    ReadWrite,
+
+    /// Open an existing file for reading only. Any attempt to write returns `Error::ReadOnly`.
+    ReadOnly,
+}
+
+/// How to handle a final line in the series file that has no newline and cannot be parsed, as
+/// happens when a process dies part way through writing it. Any other line that cannot be parsed,
+/// including a final line that does end in a newline, is always an error; use `Series::repair` to
+/// salvage such a file. A torn line inside a transaction is discarded with the rest of the
+/// uncommitted transaction, whatever this setting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TornWrite {
+    /// Refuse to open the series, returning `Error::TornWrite`.
+    Fail,
+
+    /// Discard the line. A series opened for writing truncates it from the file, so that new
+    /// entries are not appended to the broken line; a read-only series leaves the file alone.
+    Truncate,
+}
+
+/// Policy for syncing writes to disk.
+///
+/// Every write is handed to the operating system before `put`, `update` or `delete` returns, so it
+/// survives the process crashing. It survives a power failure or operating system crash only once
+/// it has been synced. `Series::flush_and_sync` syncs explicitly, whatever the policy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// Leave syncing to the operating system.
+    Never,
+
+    /// Sync after every write, before the write returns.
+    EveryWrite,
+
+    /// Sync after every `n` writes. The write that reaches `n` syncs before it returns.
+    EveryNWrites(usize),
+
+    /// Sync on the first write once this long has passed since the last sync. There is no
+    /// background timer, so a quiet series is not synced until it is next written.
+    Interval(Duration),
+}
+
+/// Policy for automatically compacting a series file.
+///
+/// Every update and delete appends a line to the file, so the file accumulates lines that no
+/// longer contribute to the live data. Compaction rewrites the file with only the live records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compaction {
+    /// Never compact automatically. `Series::compact` may still be called explicitly.
+    Manual,
+
+    /// Compact when the fraction of dead lines exceeds `ratio`, but only once the file has at
+    /// least `min_lines` lines. The policy is checked when the series is opened and after every
+    /// write.
+    DeadRatio { ratio: f64, min_lines: usize },
+}
+
+impl Compaction {
+    /// Decide whether a file with `lines` total lines, of which `live` are live records, should be
+    /// compacted.
+    pub fn should_compact(&self, lines: usize, live: usize) -> bool {
+        match *self {
+            Compaction::Manual => false,
+            Compaction::DeadRatio { ratio, min_lines } => {
+                if lines == 0 || lines < min_lines {
+                    return false;
+                }
+                let dead = lines.saturating_sub(live);
+                (dead as f64) / (lines as f64) > ratio
+            }
+        }
+    }
+}
+
+/// Options for ordering and paging through the results of `Series::search_with_options`.
+///
+/// The default returns every result in ascending timestamp order.
+#[derive(Clone, Debug, Default)]
+pub struct QueryOptions {
+    /// The direction in which results are ordered by timestamp.
+    pub order: Order,
+
+    /// Only return results strictly after this position, in the chosen order.
+    pub after: Option<Cursor>,
+
+    /// Skip this many results, after applying `after`.
+    pub offset: usize,
+
+    /// Return at most this many results.
+    pub limit: Option<usize>,
+}
+
+/// The order in which search results are returned.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Order {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// A stable position in a series, for keyset pagination. Records are ordered by timestamp, and by
+/// id among records that share a timestamp, so the cursor remains valid when records are added or
+/// removed between pages.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Cursor {
+    pub timestamp: DateTimeTz,
+    pub id: UniqueId,
+}
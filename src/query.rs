@@ -0,0 +1,468 @@
+extern crate chrono;
+extern crate chrono_tz;
+
+use self::chrono_tz::Etc::UTC;
+use std::str;
+
+use crate::criteria::{DynCriteria, EndTime, StartTime, Tags};
+use crate::date_time_tz::DateTimeTz;
+use crate::types::Error;
+
+/* A small textual query language that parses into DynCriteria. The grammar is:
+ *
+ *   query     := or_expr
+ *   or_expr   := and_expr ("or" and_expr)*
+ *   and_expr  := unary ("and" unary)*
+ *   unary     := "not" unary | "(" or_expr ")" | predicate
+ *   predicate := "time" (">=" | ">" | "<=" | "<" | "=") datetime
+ *              | "tags" "has" string
+ *   datetime  := string | <RFC3339> [<Timezone Name>]
+ *   string    := "\"" chars "\"" | word
+ *
+ * Keywords are not case sensitive.
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Op(String),
+    Quoted(String),
+    Word(String),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::LParen => String::from("\"(\""),
+            Token::RParen => String::from("\")\""),
+            Token::Op(op) => format!("\"{}\"", op),
+            Token::Quoted(s) => format!("\"\\\"{}\\\"\"", s),
+            Token::Word(w) => format!("\"{}\"", w),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        match self {
+            Token::Word(w) => w.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+}
+
+fn parse_error(position: usize, expected: &str, found: String) -> Error {
+    Error::QueryParseError {
+        position,
+        expected: String::from(expected),
+        found,
+    }
+}
+
+fn is_op_char(c: char) -> bool {
+    c == '<' || c == '>' || c == '='
+}
+
+/// Split the query into tokens, each paired with the byte offset at which it starts.
+fn tokenize(query: &str) -> Result<Vec<(usize, Token)>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = query.char_indices().peekable();
+
+    while let Some(&(pos, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push((pos, Token::LParen));
+        } else if c == ')' {
+            chars.next();
+            tokens.push((pos, Token::RParen));
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => s.push(escaped),
+                        None => {
+                            return Err(parse_error(
+                                query.len(),
+                                "a closing quote",
+                                String::from("end of input"),
+                            ))
+                        }
+                    },
+                    Some((_, other)) => s.push(other),
+                    None => {
+                        return Err(parse_error(
+                            query.len(),
+                            "a closing quote",
+                            String::from("end of input"),
+                        ))
+                    }
+                }
+            }
+            tokens.push((pos, Token::Quoted(s)));
+        } else if is_op_char(c) {
+            let mut op = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !is_op_char(c) {
+                    break;
+                }
+                op.push(c);
+                chars.next();
+            }
+            tokens.push((pos, Token::Op(op)));
+        } else {
+            let mut word = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if c.is_whitespace() || c == '(' || c == ')' || c == '"' || is_op_char(c) {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push((pos, Token::Word(word)));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    query: &'a str,
+    tokens: Vec<(usize, Token)>,
+    current: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.current).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.current)
+            .map(|(pos, _)| *pos)
+            .unwrap_or_else(|| self.query.len())
+    }
+
+    fn found(&self) -> String {
+        self.peek()
+            .map(|token| token.describe())
+            .unwrap_or_else(|| String::from("end of input"))
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self
+            .tokens
+            .get(self.current)
+            .map(|(_, token)| token.clone());
+        self.current += 1;
+        token
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek().map(|t| t.is_keyword(keyword)).unwrap_or(false) {
+            self.current += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn query(&mut self) -> Result<DynCriteria, Error> {
+        let criteria = self.or_expr()?;
+        match self.peek() {
+            None => Ok(criteria),
+            Some(_) => Err(parse_error(
+                self.position(),
+                "\"and\", \"or\", or end of input",
+                self.found(),
+            )),
+        }
+    }
+
+    fn or_expr(&mut self) -> Result<DynCriteria, Error> {
+        let mut terms = vec![self.and_expr()?];
+        while self.eat_keyword("or") {
+            terms.push(self.and_expr()?);
+        }
+        if terms.len() == 1 {
+            Ok(terms.remove(0))
+        } else {
+            Ok(DynCriteria::AnyOf(terms))
+        }
+    }
+
+    fn and_expr(&mut self) -> Result<DynCriteria, Error> {
+        let mut terms = vec![self.unary()?];
+        while self.eat_keyword("and") {
+            terms.push(self.unary()?);
+        }
+        if terms.len() == 1 {
+            Ok(terms.remove(0))
+        } else {
+            Ok(DynCriteria::AllOf(terms))
+        }
+    }
+
+    fn unary(&mut self) -> Result<DynCriteria, Error> {
+        if self.eat_keyword("not") {
+            return Ok(DynCriteria::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.advance();
+            let criteria = self.or_expr()?;
+            if self.peek() != Some(&Token::RParen) {
+                return Err(parse_error(self.position(), "\")\"", self.found()));
+            }
+            self.advance();
+            return Ok(criteria);
+        }
+        if self.eat_keyword("time") {
+            return self.time_predicate();
+        }
+        if self.eat_keyword("tags") {
+            return self.tags_predicate();
+        }
+        Err(parse_error(
+            self.position(),
+            "\"time\", \"tags\", \"not\", or \"(\"",
+            self.found(),
+        ))
+    }
+
+    fn time_predicate(&mut self) -> Result<DynCriteria, Error> {
+        let op = match self.peek() {
+            Some(Token::Op(op)) if [">=", ">", "<=", "<", "="].contains(&op.as_str()) => op.clone(),
+            _ => {
+                return Err(parse_error(
+                    self.position(),
+                    "a comparison operator (>=, >, <=, <, =)",
+                    self.found(),
+                ))
+            }
+        };
+        self.advance();
+        let time = self.datetime()?;
+
+        match op.as_str() {
+            ">=" => Ok(DynCriteria::StartTime(StartTime { time, incl: true })),
+            ">" => Ok(DynCriteria::StartTime(StartTime { time, incl: false })),
+            "<=" => Ok(DynCriteria::EndTime(EndTime { time, incl: true })),
+            "<" => Ok(DynCriteria::EndTime(EndTime { time, incl: false })),
+            _ => Ok(DynCriteria::AllOf(vec![
+                DynCriteria::StartTime(StartTime {
+                    time: time.clone(),
+                    incl: true,
+                }),
+                DynCriteria::EndTime(EndTime { time, incl: true }),
+            ])),
+        }
+    }
+
+    fn datetime(&mut self) -> Result<DateTimeTz, Error> {
+        let position = self.position();
+        let text = match self.peek() {
+            Some(Token::Quoted(s)) => s.clone(),
+            Some(Token::Word(w)) if !is_reserved(w) => {
+                let mut text = w.clone();
+                if let Some((_, Token::Word(zone))) = self.tokens.get(self.current + 1) {
+                    if !is_reserved(zone) && zone.parse::<chrono_tz::Tz>().is_ok() {
+                        text = format!("{} {}", text, zone);
+                        self.current += 1;
+                    }
+                }
+                text
+            }
+            _ => {
+                return Err(parse_error(
+                    position,
+                    "an RFC3339 date and time, optionally followed by a time zone name",
+                    self.found(),
+                ))
+            }
+        };
+        let found = self.found();
+        self.advance();
+
+        // Parse the time and the zone separately, rather than with DateTimeTz::from_str, which
+        // panics on a zone that it cannot parse. As in DateTimeTz, they are separated by a single
+        // space.
+        let parts: Vec<&str> = text.split(' ').collect();
+        let zone = match parts.len() {
+            1 => Some(UTC),
+            2 => parts[1].parse::<chrono_tz::Tz>().ok(),
+            _ => None,
+        };
+        let parsed = zone.and_then(|zone| {
+            chrono::DateTime::parse_from_rfc3339(parts[0])
+                .ok()
+                .map(|time| DateTimeTz(time.with_timezone(&zone)))
+        });
+        parsed.ok_or_else(|| {
+            parse_error(
+                position,
+                "an RFC3339 date and time, optionally followed by a time zone name",
+                found,
+            )
+        })
+    }
+
+    fn tags_predicate(&mut self) -> Result<DynCriteria, Error> {
+        if !self.eat_keyword("has") {
+            return Err(parse_error(self.position(), "\"has\"", self.found()));
+        }
+        match self.peek() {
+            Some(Token::Quoted(s)) => {
+                let tag = s.clone();
+                self.advance();
+                Ok(DynCriteria::Tags(Tags { tags: vec![tag] }))
+            }
+            Some(Token::Word(w)) if !is_reserved(w) => {
+                let tag = w.clone();
+                self.advance();
+                Ok(DynCriteria::Tags(Tags { tags: vec![tag] }))
+            }
+            _ => Err(parse_error(self.position(), "a tag", self.found())),
+        }
+    }
+}
+
+fn is_reserved(word: &str) -> bool {
+    ["and", "or", "not"]
+        .iter()
+        .any(|keyword| word.eq_ignore_ascii_case(keyword))
+}
+
+impl str::FromStr for DynCriteria {
+    type Err = Error;
+
+    /// Parse a textual query, such as
+    /// `time >= 2019-05-01T00:00:00Z US/Central and tags has "run" and not tags has "treadmill"`.
+    /// Raise QueryParseError, with the byte offset of the offending token, if the parsing fails.
+    fn from_str(query: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            query,
+            tokens: tokenize(query)?,
+            current: 0,
+        };
+        parser.query()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::criteria::{DynCriteria, EndTime, StartTime, Tags};
+    use crate::date_time_tz::DateTimeTz;
+    use crate::types::Error;
+    use chrono::TimeZone;
+    use chrono_tz::Etc::UTC;
+    use chrono_tz::US::Central;
+
+    fn tag(t: &str) -> DynCriteria {
+        DynCriteria::Tags(Tags {
+            tags: vec![String::from(t)],
+        })
+    }
+
+    #[test]
+    fn it_parses_a_compound_query() {
+        let criteria: DynCriteria =
+            "time >= 2019-05-01T00:00:00Z US/Central and tags has \"run\" and not tags has \"treadmill\""
+                .parse()
+                .unwrap();
+        assert_eq!(
+            criteria,
+            DynCriteria::AllOf(vec![
+                DynCriteria::StartTime(StartTime {
+                    time: DateTimeTz(Central.with_ymd_and_hms(2019, 4, 30, 19, 0, 0).unwrap()),
+                    incl: true,
+                }),
+                tag("run"),
+                DynCriteria::Not(Box::new(tag("treadmill"))),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_gives_and_precedence_over_or() {
+        let criteria: DynCriteria = "tags has a or tags has b and (tags has c OR tags has d)"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            criteria,
+            DynCriteria::AnyOf(vec![
+                tag("a"),
+                DynCriteria::AllOf(vec![tag("b"), DynCriteria::AnyOf(vec![tag("c"), tag("d")])]),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_parses_time_comparisons() {
+        let time = DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 1, 0, 0, 0).unwrap());
+        let criteria: DynCriteria = "time<\"2019-05-01T00:00:00Z\"".parse().unwrap();
+        assert_eq!(
+            criteria,
+            DynCriteria::EndTime(EndTime {
+                time: time.clone(),
+                incl: false
+            })
+        );
+
+        let criteria: DynCriteria = "time = 2019-05-01T00:00:00+00:00".parse().unwrap();
+        assert_eq!(
+            criteria,
+            DynCriteria::AllOf(vec![
+                DynCriteria::StartTime(StartTime {
+                    time: time.clone(),
+                    incl: true
+                }),
+                DynCriteria::EndTime(EndTime { time, incl: true }),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_reports_the_position_and_expected_token() {
+        match "tags has run and time => 2019-05-01T00:00:00Z".parse::<DynCriteria>() {
+            Err(Error::QueryParseError {
+                position, found, ..
+            }) => {
+                assert_eq!(position, 22);
+                assert_eq!(found, "\"=>\"");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        match "time >= yesterday".parse::<DynCriteria>() {
+            Err(Error::QueryParseError { position, .. }) => assert_eq!(position, 8),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        match "(tags has run".parse::<DynCriteria>() {
+            Err(Error::QueryParseError {
+                position,
+                expected,
+                found,
+            }) => {
+                assert_eq!(position, 13);
+                assert_eq!(expected, "\")\"");
+                assert_eq!(found, "end of input");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        match "time >= \"2019-05-01T00:00:00Z Not/AZone\"".parse::<DynCriteria>() {
+            Err(Error::QueryParseError { position, .. }) => assert_eq!(position, 8),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        match "time >= \"2019-05-01T00:00:00Z US/Central\t\"".parse::<DynCriteria>() {
+            Err(Error::QueryParseError { position, .. }) => assert_eq!(position, 8),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
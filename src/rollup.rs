@@ -0,0 +1,325 @@
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use std::collections::BTreeMap;
+
+use crate::aggregate::{summarize, Bucket, Reducer, Summary};
+use crate::criteria::{exact_time, time_range};
+use crate::date_time_tz::DateTimeTz;
+use crate::series::Series;
+use crate::types::{Error, Record, Recordable};
+
+/// One bucket of a rollup series: a summary of the values of all of the source records that fall
+/// into the bucket.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RollupRecord {
+    pub bucket: DateTimeTz,
+    pub count: usize,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    pub first: f64,
+    pub last: f64,
+
+    /// The highest sequence number of the source records that had been written when this bucket
+    /// was last brought up to date.
+    #[serde(default)]
+    pub source_seq: u64,
+}
+
+impl RollupRecord {
+    fn new(bucket: DateTimeTz, summary: &Summary, source_seq: u64) -> RollupRecord {
+        RollupRecord {
+            bucket,
+            count: summary.count,
+            sum: summary.sum,
+            min: summary.min,
+            max: summary.max,
+            first: summary.first,
+            last: summary.last,
+            source_seq,
+        }
+    }
+
+    fn summary(&self) -> Summary {
+        Summary {
+            count: self.count,
+            sum: self.sum,
+            min: self.min,
+            max: self.max,
+            first: self.first,
+            last: self.last,
+        }
+    }
+
+    /// Reduce the bucket to a single value.
+    pub fn reduce(&self, reducer: Reducer) -> f64 {
+        self.summary().reduce(reducer)
+    }
+}
+
+impl Recordable for RollupRecord {
+    fn timestamp(&self) -> DateTimeTz {
+        self.bucket.clone()
+    }
+
+    fn tags(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Maintains a series of `RollupRecord`s derived from a source series, such as minute or hour
+/// summaries of high-rate sensor data. The raw data can then be kept for a short window while the
+/// rollups are kept forever.
+///
+/// Each `run` picks up the source records written since the previous run, by their sequence
+/// numbers, and writes only the buckets that they fall into. Every rollup records the highest
+/// source sequence number that it has seen, so a run after a restart carries on where the last
+/// one stopped.
+///
+/// A touched bucket that lies entirely within the source's `retention` window is rebuilt from the
+/// records in the source, which picks up updates to records that were already rolled up. A
+/// bucket that starts before the retention cutoff may have lost some of its raw records, so the
+/// new records are added to its existing summary instead, after the values already in it.
+///
+/// Rollups assume that records only leave the source through its `retention` option. A record
+/// that is deleted, or updated to move it to another bucket, stays in its old bucket until that
+/// bucket is next rebuilt. A record updated in place in a bucket that starts before the retention
+/// cutoff is counted again, alongside its old value, since the summary cannot tell the two apart.
+pub struct Rollup<F> {
+    bucket: Bucket,
+    extract: F,
+}
+
+impl<F> Rollup<F> {
+    /// Create a rollup that groups source records into `bucket`s, and summarizes the values that
+    /// `extract` pulls out of each record.
+    pub fn new(bucket: Bucket, extract: F) -> Rollup<F> {
+        Rollup { bucket, extract }
+    }
+
+    /// Bring the target series up to date with the source series, returning the number of
+    /// buckets written.
+    pub fn run<T>(
+        &self,
+        source: &Series<T>,
+        target: &mut Series<RollupRecord>,
+    ) -> Result<usize, Error>
+    where
+        T: Clone + Recordable + DeserializeOwned + Serialize,
+        F: Fn(&T) -> f64,
+    {
+        let high_water = target
+            .records()?
+            .map(|record| record.data.source_seq)
+            .max()
+            .unwrap_or(0);
+        let mut source_seq = high_water;
+        let mut touched: BTreeMap<DateTimeTz, Vec<(DateTimeTz, f64)>> = BTreeMap::new();
+        for record in source.records()? {
+            let seq = record.sequence().unwrap_or(0);
+            if seq <= high_water {
+                continue;
+            }
+            source_seq = source_seq.max(seq);
+            let time = record.timestamp();
+            touched
+                .entry(self.bucket.start_of(&time))
+                .or_default()
+                .push((time, (self.extract)(&record.data)));
+        }
+
+        let cutoff = source
+            .options()
+            .retention
+            .map(|retention| DateTimeTz::now().map(|now| now - retention));
+        // The buckets are written in a single batch, so that the high-water mark never gets ahead
+        // of the buckets actually written.
+        let mut rollups = Vec::new();
+        for (start, mut values) in touched {
+            let existing = target.search(exact_time(start.clone()))?.into_iter().next();
+            // Whether the source still holds every raw record in the bucket.
+            let complete = match cutoff {
+                Some(ref cutoff) => start >= *cutoff,
+                None => true,
+            };
+            let summary = match existing {
+                Some(ref existing) if !complete => {
+                    values.sort_by(|l, r| l.0.cmp(&r.0));
+                    let mut summary = existing.data.summary();
+                    for (_, value) in values {
+                        summary.add(value);
+                    }
+                    summary
+                }
+                _ => match self.rebuild(source, &start)? {
+                    Some(summary) => summary,
+                    None => continue,
+                },
+            };
+            let rollup = RollupRecord::new(start, &summary, source_seq);
+            rollups.push(match existing {
+                Some(existing) => Record::with_id(existing.id, rollup),
+                None => Record::new(rollup),
+            });
+        }
+        let written = rollups.len();
+        if written > 0 {
+            target.update_batch(rollups)?;
+        }
+        Ok(written)
+    }
+
+    /// Summarize the records in the source that fall into the bucket starting at `start`, or
+    /// return None if there are none.
+    fn rebuild<T>(&self, source: &Series<T>, start: &DateTimeTz) -> Result<Option<Summary>, Error>
+    where
+        T: Clone + Recordable + DeserializeOwned + Serialize,
+        F: Fn(&T) -> f64,
+    {
+        let (lower, upper) = self.bucket.bounds_of(start);
+        let values = source
+            .search(time_range(lower, true, upper, true))?
+            .into_iter()
+            .map(|record| (record.timestamp(), (self.extract)(&record.data)))
+            .filter(|(time, _)| self.bucket.start_of(time) == *start);
+        Ok(summarize(&self.bucket, values)
+            .into_iter()
+            .next()
+            .map(|(_, summary)| summary))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rollup, RollupRecord};
+    use crate::aggregate::{Bucket, Reducer};
+    use crate::date_time_tz::DateTimeTz;
+    use crate::options::SeriesOptions;
+    use crate::series::Series;
+    use crate::types::{Record, Recordable};
+    use chrono::{Duration, TimeZone};
+    use chrono_tz::Etc::UTC;
+
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    struct Reading {
+        time: DateTimeTz,
+        depth: f64,
+    }
+
+    impl Recordable for Reading {
+        fn timestamp(&self) -> DateTimeTz {
+            self.time.clone()
+        }
+
+        fn tags(&self) -> Vec<String> {
+            Vec::new()
+        }
+    }
+
+    fn reading(min: u32, sec: u32, depth: f64) -> Reading {
+        Reading {
+            time: DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 15, 12, min, sec).unwrap()),
+            depth,
+        }
+    }
+
+    fn minute(min: u32) -> DateTimeTz {
+        DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 15, 12, min, 0).unwrap())
+    }
+
+    #[test]
+    fn it_rolls_up_only_touched_buckets() {
+        let source_file = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let target_file = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let mut source: Series<Reading> = Series::open(&source_file.to_string_lossy()).unwrap();
+        let mut target: Series<RollupRecord> =
+            Series::open(&target_file.to_string_lossy()).unwrap();
+        let rollup = Rollup::new(Bucket::Fixed(Duration::minutes(1)), |r: &Reading| r.depth);
+
+        source.put(reading(0, 10, 4.0)).unwrap();
+        source.put(reading(0, 40, 6.0)).unwrap();
+        let moved = source.put(reading(1, 5, 5.0)).unwrap();
+        source.put(reading(2, 0, 7.0)).unwrap();
+        assert_eq!(rollup.run(&source, &mut target).unwrap(), 3);
+        assert_eq!(rollup.run(&source, &mut target).unwrap(), 0);
+
+        let rollups = target.all_records().unwrap();
+        assert_eq!(rollups.len(), 3);
+        let first = target
+            .search(crate::criteria::exact_time(minute(0)))
+            .unwrap();
+        assert_eq!(first[0].data.reduce(Reducer::Mean), 5.0);
+        assert_eq!(first[0].data.reduce(Reducer::First), 4.0);
+        assert_eq!(first[0].data.reduce(Reducer::Last), 6.0);
+
+        // The moved record stays in the bucket for minute 1, which is not touched again.
+        source.put(reading(2, 30, 9.0)).unwrap();
+        source
+            .update(Record::with_id(moved, reading(3, 0, 5.0)))
+            .unwrap();
+        assert_eq!(rollup.run(&source, &mut target).unwrap(), 2);
+
+        drop(target);
+        let target: Series<RollupRecord> = Series::open(&target_file.to_string_lossy()).unwrap();
+        let rollups: Vec<(DateTimeTz, f64)> = target
+            .filter_iter(|_| true)
+            .unwrap()
+            .map(|r| (r.data.bucket.clone(), r.data.reduce(Reducer::Max)))
+            .collect();
+        assert_eq!(
+            rollups,
+            vec![
+                (minute(0), 6.0),
+                (minute(1), 5.0),
+                (minute(2), 9.0),
+                (minute(3), 5.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn it_keeps_buckets_whose_raw_data_has_expired() {
+        let source_file = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let target_file = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let now = DateTimeTz::now();
+        let ago = |hours: i64, depth: f64| Reading {
+            time: now.map(|t| t - Duration::hours(hours)),
+            depth,
+        };
+        // Buckets long enough that the retention cutoff falls inside the current one.
+        let bucket = Bucket::Fixed(Duration::days(10000));
+        let mut target: Series<RollupRecord> =
+            Series::open(&target_file.to_string_lossy()).unwrap();
+
+        {
+            let mut source: Series<Reading> = Series::open(&source_file.to_string_lossy()).unwrap();
+            source.put(ago(240, 4.0)).unwrap();
+            source.put(ago(200, 6.0)).unwrap();
+            let rollup = Rollup::new(bucket, |r: &Reading| r.depth);
+            assert_eq!(rollup.run(&source, &mut target).unwrap(), 1);
+        }
+
+        let mut source: Series<Reading> = Series::open_with_options(
+            &source_file.to_string_lossy(),
+            SeriesOptions {
+                retention: Some(Duration::days(1)),
+                ..SeriesOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(source.all_records().unwrap().is_empty());
+
+        // A new rollup, as after a restart, carries on from the rollups already written.
+        let rollup = Rollup::new(bucket, |r: &Reading| r.depth);
+        assert_eq!(rollup.run(&source, &mut target).unwrap(), 0);
+        source.put(ago(1, 8.0)).unwrap();
+        assert_eq!(rollup.run(&source, &mut target).unwrap(), 1);
+
+        let rollups = target.all_records().unwrap();
+        assert_eq!(rollups.len(), 1);
+        assert_eq!(rollups[0].data.reduce(Reducer::Count), 3.0);
+        assert_eq!(rollups[0].data.reduce(Reducer::Max), 8.0);
+        assert_eq!(rollups[0].data.reduce(Reducer::First), 4.0);
+        assert_eq!(rollups[0].data.reduce(Reducer::Last), 8.0);
+    }
+}
@@ -1,26 +1,165 @@
+extern crate fs2;
 extern crate serde;
 extern crate serde_json;
 extern crate uuid;
 
+use self::fs2::FileExt;
 use self::serde::de::DeserializeOwned;
 use self::serde::ser::Serialize;
+use self::serde_json::Value;
+use chrono::Timelike;
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::io;
+use std::io::{BufWriter, LineWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::str;
+use std::time::Instant;
+
+use crate::aggregate::{summarize, Bucket, Reducer};
+use crate::criteria::{Criteria, TimeBounds};
+use crate::date_time_tz::DateTimeTz;
+use crate::frame;
+use crate::index::{TagIndex, TimeIndex};
+use crate::options::{
+    Access, Durability, Migrations, Order, QueryOptions, SeriesOptions, TornWrite,
+};
+use crate::transaction::Transaction;
+use crate::types::{
+    DeletableRecord, Entry, Error, Header, Marker, Metadata, Record, Recordable, RepairReport,
+    UniqueId, Version, FORMAT_VERSION,
+};
+
+/// Convert an error from taking a file lock, distinguishing a lock held by another process from
+/// other IO errors.
+fn lock_error(err: io::Error) -> Error {
+    if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+        Error::Locked
+    } else {
+        Error::IOError(err)
+    }
+}
+
+/// Open the series file at `path` for the access that `options` ask for, and take the lock that
+/// they call for. Compaction replaces the file at the path with a new one, so once the lock is
+/// held, check that the path still names the locked file, and start again if it does not.
+fn open_locked(path: &str, options: &SeriesOptions) -> Result<File, Error> {
+    loop {
+        let f = match options.access {
+            Access::ReadWrite => OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path),
+            Access::ReadOnly => OpenOptions::new().read(true).open(path),
+        }
+        .map_err(Error::IOError)?;
+        if !options.lock {
+            return Ok(f);
+        }
+
+        let res = match options.access {
+            Access::ReadWrite => FileExt::try_lock_exclusive(&f),
+            Access::ReadOnly => FileExt::try_lock_shared(&f),
+        };
+        res.map_err(lock_error)?;
+        match fs::metadata(path) {
+            Ok(current) if same_file(&f, &current)? => return Ok(f),
+            Ok(_) => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(Error::IOError(err)),
+        }
+    }
+}
+
+/// Sync a directory, so that a file renamed into it stays there after a power failure.
+fn sync_dir(dir: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        File::open(dir)
+            .and_then(|d| d.sync_all())
+            .map_err(Error::IOError)
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        Ok(())
+    }
+}
+
+/// Whether the open file `f` is the file described by `current`.
+fn same_file(f: &File, current: &fs::Metadata) -> Result<bool, Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let open = f.metadata().map_err(Error::IOError)?;
+        Ok((current.dev(), current.ino()) == (open.dev(), open.ino()))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (f, current);
+        Ok(true)
+    }
+}
 
-use crate::criteria::Criteria;
-use crate::types::{DeletableRecord, Error, Record, Recordable, UniqueId};
+/// The entries read from part of the series file.
+struct Scan<T: Clone + Recordable> {
+    /// The entries that take effect, in the order that they were written.
+    entries: Vec<DeletableRecord<T>>,
+    /// The number of lines read.
+    lines: usize,
+    /// The number of bytes read.
+    consumed: usize,
+    /// The file header, if the scan started at the beginning of a file that has one.
+    header: Option<Header>,
+}
+
+/// Serialize a file header as the first line of a series file.
+fn header_line(header: &Header) -> Result<String, Error> {
+    serde_json::to_string(&serde_json::json!({ "header": header })).map_err(Error::JSONStringError)
+}
+
+/// Give `entry` its sequence number and advance `last`, the highest number so far, past it. Lines
+/// written by older versions of this library have no sequence number, and take the next one
+/// after `last`.
+fn number_entry<T: Clone + Recordable>(entry: &mut DeletableRecord<T>, last: &mut u64) {
+    let seq = entry.meta.seq.unwrap_or(*last + 1);
+    entry.meta.seq = Some(seq);
+    *last = (*last).max(seq);
+}
 
 /// An open time series database.
 ///
 /// Any given database can store only one data type, T. The data type must be determined when the
 /// database is opened.
 pub struct Series<T: Clone + Recordable + DeserializeOwned + Serialize> {
-    //path: String,
-    writer: LineWriter<File>,
+    path: String,
+    options: SeriesOptions,
+    /// The open series file, which also holds the advisory lock if there is one.
+    file: File,
+    /// A writer that appends to the file, absent if the series was opened read-only.
+    writer: Option<LineWriter<File>>,
     records: HashMap<UniqueId, Record<T>>,
+    /// The highest sequence number written to the file so far.
+    seq: u64,
+    /// The header line of the file, if it has one.
+    header: Option<Header>,
+    time_index: TimeIndex,
+    tag_index: TagIndex,
+    /// The number of lines in the file, both live records and dead ones.
+    lines: usize,
+    /// The number of bytes of the file that have been read or written so far.
+    offset: u64,
+    /// The number of writes since the file was last synced.
+    unsynced: usize,
+    /// When the file was last synced, or opened.
+    last_sync: Instant,
 }
 
 impl<T> Series<T>
@@ -30,53 +169,744 @@ where
     /// Open a time series database at the specified path. `path` is the full path and filename for
     /// the database.
     pub fn open(path: &str) -> Result<Series<T>, Error> {
-        let f = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&path)
+        Series::open_with_options(path, SeriesOptions::default())
+    }
+
+    /// Open a time series database at the specified path, with explicit options for how the file
+    /// is to be maintained.
+    ///
+    /// Unless `options.lock` is false, this takes an advisory lock on the file: an exclusive lock
+    /// when opened for writing, and a shared lock when opened read-only. If another process holds
+    /// a conflicting lock, this returns `Error::Locked` rather than waiting. If another process
+    /// compacts the file while the lock is being taken, the new file is locked instead.
+    pub fn open_with_options(path: &str, options: SeriesOptions) -> Result<Series<T>, Error> {
+        let f = open_locked(path, &options)?;
+
+        let writer = match options.access {
+            Access::ReadWrite => Some(LineWriter::new(f.try_clone().map_err(Error::IOError)?)),
+            Access::ReadOnly => None,
+        };
+
+        let mut series = Series {
+            path: String::from(path),
+            options,
+            file: f,
+            writer,
+            records: HashMap::new(),
+            seq: 0,
+            header: None,
+            time_index: TimeIndex::new(),
+            tag_index: TagIndex::new(),
+            lines: 0,
+            offset: 0,
+            unsynced: 0,
+            last_sync: Instant::now(),
+        };
+        // A reader may be following a writer that is part way through an append, so it leaves
+        // an unfinished final line for `refresh`.
+        series.read_entries(series.writer.is_none())?;
+        if series.options.header && series.writer.is_some() && series.lines == 0 {
+            series.write_header()?;
+        }
+        // Keep writing the file the way that its header says it was created.
+        if let Some(ref header) = series.header {
+            series.options.checksums |= header.checksums;
+        }
+        series.drop_expired();
+        if series.options.rewrite_migrated && series.writer.is_some() && series.has_migrated() {
+            series.compact()?;
+        }
+        series.maybe_compact()?;
+        Ok(series)
+    }
+
+    /// Open an existing time series database for reading only, typically to follow a file that
+    /// another process is writing. The file is never created or appended to, and no lock is
+    /// taken, so this succeeds even while a writer holds its exclusive lock. A final line that the
+    /// writer has not finished yet is left unread. Call `refresh` to pick up records that the
+    /// writer has added since the series was opened.
+    pub fn open_read_only(path: &str) -> Result<Series<T>, Error> {
+        Series::open_with_options(
+            path,
+            SeriesOptions {
+                access: Access::ReadOnly,
+                lock: false,
+                ..SeriesOptions::default()
+            },
+        )
+    }
+
+    /// Pick up any entries that have been appended to the file since it was last read, applying
+    /// new records, updates and deletions to the in-memory view. Returns the number of entries
+    /// read.
+    ///
+    /// A final line without a newline is left for a later refresh, since the writer may still be
+    /// in the middle of appending it. If the file has been replaced, for instance because the
+    /// writer compacted it, or has been truncated, the series is reloaded from scratch, and the
+    /// new file is locked as the old one was.
+    pub fn refresh(&mut self) -> Result<usize, Error> {
+        if self.replaced()? {
+            self.file = open_locked(&self.path, &self.options)?;
+            if self.writer.is_some() {
+                self.writer = Some(LineWriter::new(
+                    self.file.try_clone().map_err(Error::IOError)?,
+                ));
+            }
+            self.records = HashMap::new();
+            self.seq = 0;
+            self.header = None;
+            self.time_index = TimeIndex::new();
+            self.tag_index = TagIndex::new();
+            self.lines = 0;
+            self.offset = 0;
+        }
+        let count = self.read_entries(true)?;
+        self.drop_expired();
+        Ok(count)
+    }
+
+    /// Whether the file at the series path is no longer the file that this series has been
+    /// reading, or has been truncated below the offset already read.
+    fn replaced(&self) -> Result<bool, Error> {
+        let current = fs::metadata(&self.path).map_err(Error::IOError)?;
+        Ok(current.len() < self.offset || !same_file(&self.file, &current)?)
+    }
+
+    /// Read the entries in the file after the current offset, and apply them to the records and
+    /// indexes. Returns the number of entries read.
+    ///
+    /// When `tail` is set, a final line without a newline is left unread. Otherwise it is read
+    /// like any other line: if it cannot be parsed it is handled according to the `torn_write`
+    /// option, and if it can, a writer adds the missing newline before anything else is appended.
+    fn read_entries(&mut self, tail: bool) -> Result<usize, Error> {
+        let mut buf = Vec::new();
+        (&self.file)
+            .seek(SeekFrom::Start(self.offset))
             .map_err(Error::IOError)?;
+        (&self.file).read_to_end(&mut buf).map_err(Error::IOError)?;
 
-        let records = Series::load_file(&f)?;
+        let complete = match buf.iter().rposition(|b| *b == b'\n') {
+            Some(pos) if tail => pos + 1,
+            None if tail => 0,
+            _ => buf.len(),
+        };
+
+        let scan = self.scan(&buf[..complete], self.lines)?;
+        // Anything after the scanned part is a torn final line or an uncommitted transaction. A
+        // writer removes it from the file, so that new entries are not appended to it.
+        if self.writer.is_some() && scan.consumed < complete {
+            self.file
+                .set_len(self.offset + scan.consumed as u64)
+                .map_err(Error::IOError)?;
+        }
+        let mut consumed = scan.consumed as u64;
+        // A final line that parses was written in full apart from its newline. Finish it, so
+        // that the next entry does not land on the same line.
+        if scan.consumed == buf.len() && !buf.is_empty() && !buf.ends_with(b"\n") {
+            if let Some(writer) = self.writer.as_mut() {
+                writer.write_all(b"\n").map_err(Error::IOError)?;
+                consumed += 1;
+            }
+        }
+        for entry in scan.entries {
+            self.apply(entry);
+        }
+        if scan.header.is_some() {
+            self.header = scan.header;
+        }
 
-        let writer = LineWriter::new(f);
+        self.lines += scan.lines;
+        self.offset += consumed;
+        Ok(scan.lines)
+    }
 
-        Ok(Series {
-            //path: String::from(path),
-            writer,
-            records,
+    /// Parse the lines in `buf`, which start at line `first_line + 1` of the file, and return
+    /// the entries that take effect. Entries inside a transaction take effect only if the
+    /// transaction's commit marker is also in `buf`.
+    ///
+    /// Only a final line without a newline can be torn, so one that cannot be parsed is handled
+    /// according to the `torn_write` option, unless it is inside a transaction, which is then
+    /// simply uncommitted. Any other line that cannot be parsed is an error. The scan stops
+    /// before a torn line, or before the begin marker of a transaction that has no commit.
+    fn scan(&self, buf: &[u8], first_line: usize) -> Result<Scan<T>, Error> {
+        let mut entries = Vec::new();
+        let mut count = 0;
+        let mut consumed = 0;
+        // A transaction whose commit has not been read yet: its id, its entries, and the count
+        // and position of its begin marker.
+        let mut pending: Option<(UniqueId, Vec<DeletableRecord<T>>, usize, usize)> = None;
+        let mut header = None;
+        for line in buf.split_inclusive(|b| *b == b'\n') {
+            let line_no = first_line + count + 1;
+            match Series::parse_entry(line, line_no, &self.options.migrations) {
+                // The header is only recognized on the first line, and is checked before
+                // anything else in the file is parsed.
+                Ok(Some(Entry::Header(h))) if line_no == 1 => {
+                    h.check()?;
+                    header = Some(h);
+                }
+                Ok(Some(Entry::Header(_))) => (),
+                Ok(Some(Entry::Record(entry))) => match pending {
+                    Some((_, ref mut tx_entries, _, _)) => tx_entries.push(entry),
+                    None => entries.push(entry),
+                },
+                Ok(Some(Entry::Marker(Marker::Begin(id)))) => {
+                    pending = Some((id, Vec::new(), count, consumed));
+                }
+                Ok(Some(Entry::Marker(Marker::Commit(id)))) => match pending.take() {
+                    Some((begin, tx_entries, _, _)) if begin == id => entries.extend(tx_entries),
+                    _ => (),
+                },
+                Ok(None) => (),
+                Err(err) if line.ends_with(b"\n") => return Err(err),
+                Err(_) if pending.is_some() => break,
+                Err(_) => match self.options.torn_write {
+                    TornWrite::Fail => return Err(Error::TornWrite { line: line_no }),
+                    TornWrite::Truncate => break,
+                },
+            }
+            count += 1;
+            consumed += line.len();
+        }
+
+        if let Some((_, _, begin_count, begin_consumed)) = pending {
+            count = begin_count;
+            consumed = begin_consumed;
+        }
+
+        Ok(Scan {
+            entries,
+            lines: count,
+            consumed,
+            header,
         })
     }
 
-    /// Load a file and return all of the records in it.
-    fn load_file(f: &File) -> Result<HashMap<UniqueId, Record<T>>, Error> {
+    /// Every version of a record that is still in the file, in the order that they were written,
+    /// including the deletions. The history is read from the file, so versions that have been
+    /// discarded by compaction are not included.
+    pub fn history(&self, id: &UniqueId) -> Result<Vec<Version<T>>, Error> {
+        Ok(self
+            .read_writes()?
+            .into_iter()
+            .filter(|entry| entry.id == *id)
+            .map(|entry| Version {
+                sequence: entry.meta.seq.unwrap_or_default(),
+                data: entry.data,
+                meta: entry.meta,
+            })
+            .collect())
+    }
+
+    /// The records as they were just after write `sequence`, in time order. Writes are numbered
+    /// by their sequence numbers, matching `Version::sequence`, so `as_of(0)` is empty. As with
+    /// `history`, writes discarded by compaction are not included.
+    pub fn as_of(&self, sequence: u64) -> Result<Vec<Record<T>>, Error> {
+        Ok(Series::replay(self.read_writes()?.into_iter().filter(
+            |entry| matches!(entry.meta.seq, Some(seq) if seq <= sequence),
+        )))
+    }
+
+    /// Every write in the file that takes effect, each numbered as it was when it was applied to
+    /// the series, in the order that they were written. Compaction rewrites the file in time
+    /// order, so this is not necessarily the order of the lines in the file.
+    ///
+    /// Only the part of the file that has been loaded into the series is read, so that the
+    /// history agrees with the in-memory view and ignores an append that is still in progress.
+    fn read_writes(&self) -> Result<Vec<DeletableRecord<T>>, Error> {
+        let mut buf = Vec::new();
+        (&self.file)
+            .seek(SeekFrom::Start(0))
+            .map_err(Error::IOError)?;
+        (&self.file)
+            .take(self.offset)
+            .read_to_end(&mut buf)
+            .map_err(Error::IOError)?;
+        let mut last = 0;
+        let mut entries: Vec<DeletableRecord<T>> = self
+            .scan(&buf, 0)?
+            .entries
+            .into_iter()
+            .map(|mut entry| {
+                number_entry(&mut entry, &mut last);
+                entry
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.meta.seq);
+        Ok(entries)
+    }
+
+    /// The records as they were at wall-clock time `time`, in time order, according to the write
+    /// times recorded in the file. Lines written without a write time, by older versions of this
+    /// library, are taken to have been written before any line that has one.
+    pub fn as_of_time(&self, time: &DateTimeTz) -> Result<Vec<Record<T>>, Error> {
+        Ok(Series::replay(self.read_writes()?.into_iter().filter(
+            |entry| match entry.meta.written {
+                Some(ref written) => written <= time,
+                None => true,
+            },
+        )))
+    }
+
+    /// Apply a sequence of entries to an empty series, and return the resulting records in time
+    /// order.
+    fn replay<I: Iterator<Item = DeletableRecord<T>>>(entries: I) -> Vec<Record<T>> {
         let mut records: HashMap<UniqueId, Record<T>> = HashMap::new();
-        let reader = BufReader::new(f);
-        for line in reader.lines() {
-            match line {
-                Ok(line_) => {
-                    match line_.parse::<DeletableRecord<_>>() {
-                        Ok(record) => match record.data {
-                            Some(val) => records.insert(
-                                record.id.clone(),
-                                Record {
-                                    id: record.id.clone(),
-                                    data: val,
-                                },
-                            ),
-                            None => records.remove(&record.id.clone()),
-                        },
-                        Err(err) => return Err(err),
-                    };
+        for entry in entries {
+            match entry.data {
+                Some(data) => records.insert(
+                    entry.id.clone(),
+                    Record {
+                        id: entry.id,
+                        data,
+                        meta: entry.meta,
+                    },
+                ),
+                None => records.remove(&entry.id),
+            };
+        }
+        let mut records: Vec<Record<T>> = records.into_values().collect();
+        records.sort_by(|l, r| (l.timestamp(), &l.id).cmp(&(r.timestamp(), &r.id)));
+        records
+    }
+
+    /// Restore a deleted record to its last version before it was deleted. The restored version is
+    /// appended to the file as a new write.
+    pub fn undelete(&mut self, id: &UniqueId) -> Result<(), Error> {
+        if self.records.contains_key(id) {
+            return Err(Error::NotDeleted(id.clone()));
+        }
+        let data = self
+            .history(id)?
+            .into_iter()
+            .rev()
+            .find_map(|version| version.data)
+            .ok_or_else(|| Error::RecordNotFound(id.clone()))?;
+        self.update(Record::with_id(id.clone(), data))
+    }
+
+    /// Make an earlier version of a record, identified by its `Version::sequence`, the current
+    /// version again. The version is appended to the file as a new write, so the versions written
+    /// since remain in the history. This also restores a record that has been deleted.
+    pub fn revert(&mut self, id: &UniqueId, sequence: u64) -> Result<(), Error> {
+        let data = self
+            .history(id)?
+            .into_iter()
+            .find(|version| version.sequence == sequence)
+            .and_then(|version| version.data)
+            .ok_or_else(|| Error::NoSuchVersion {
+                id: id.clone(),
+                sequence,
+            })?;
+        self.update(Record::with_id(id.clone(), data))
+    }
+
+    /// Parse a single line of the file, returning None for a blank line. `line_no` is the 1-based
+    /// line number, used to report a checksum mismatch. Record data is upgraded with `migrations`
+    /// before it is deserialized.
+    fn parse_entry(
+        line: &[u8],
+        line_no: usize,
+        migrations: &Migrations,
+    ) -> Result<Option<Entry<T>>, Error> {
+        let line = str::from_utf8(line)
+            .map_err(|err| Error::IOError(io::Error::new(io::ErrorKind::InvalidData, err)))?
+            .trim_end_matches(&['\n', '\r'][..]);
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let value: Value =
+            serde_json::from_str(frame::decode(line, line_no)?).map_err(Error::JSONParseError)?;
+        if value.get("id").is_none() {
+            if let Some(header) = value.get("header") {
+                return serde_json::from_value(header.clone())
+                    .map(|header| Some(Entry::Header(header)))
+                    .map_err(Error::JSONParseError);
+            }
+            if let Ok(marker) = serde_json::from_value(value.clone()) {
+                return Ok(Some(Entry::Marker(marker)));
+            }
+        }
+        let mut value = value;
+        let schema = value.get("schema").and_then(Value::as_u64).unwrap_or(0) as u32;
+        if let Some(data) = value.get_mut("data") {
+            if !data.is_null() {
+                *data = migrations.upgrade(data.take(), schema)?;
+            }
+        }
+        serde_json::from_value(value)
+            .map(|entry| Some(Entry::Record(entry)))
+            .map_err(Error::JSONParseError)
+    }
+
+    /// Scan the series file at `path` for lines that cannot be parsed, and write every line that
+    /// can be parsed to a salvaged copy at `<path>.salvaged`. The original file is left as it is,
+    /// so that it can be inspected before the salvaged copy is moved into its place.
+    ///
+    /// This takes no lock, so it should not be run while another process is writing the file.
+    pub fn repair(path: &str) -> Result<RepairReport, Error> {
+        Series::<T>::repair_with_options(path, SeriesOptions::default())
+    }
+
+    /// Repair the series file at `path` as `repair` does, using the migrations in `options` to
+    /// read entries written with older versions of the record type.
+    pub fn repair_with_options(path: &str, options: SeriesOptions) -> Result<RepairReport, Error> {
+        let buf = fs::read(path).map_err(Error::IOError)?;
+        let salvaged = format!("{}.salvaged", path);
+        let mut w = BufWriter::new(File::create(&salvaged).map_err(Error::IOError)?);
+
+        let mut lines = 0;
+        let mut failed = Vec::new();
+        for line in buf.split_inclusive(|b| *b == b'\n') {
+            lines += 1;
+            match Series::<T>::parse_entry(line, lines, &options.migrations) {
+                Ok(Some(_)) => {
+                    w.write_all(line).map_err(Error::IOError)?;
+                    if !line.ends_with(b"\n") {
+                        w.write_all(b"\n").map_err(Error::IOError)?;
+                    }
+                }
+                Ok(None) => (),
+                Err(_) => failed.push(lines),
+            }
+        }
+        w.flush().map_err(Error::IOError)?;
+
+        Ok(RepairReport {
+            lines,
+            failed,
+            salvaged,
+        })
+    }
+
+    /// Apply a single entry from the file to the records and indexes.
+    fn apply(&mut self, mut entry: DeletableRecord<T>) {
+        number_entry(&mut entry, &mut self.seq);
+        if let Some(old) = self.records.remove(&entry.id) {
+            self.unindex(&old);
+        }
+        if let Some(data) = entry.data {
+            let record = Record {
+                id: entry.id.clone(),
+                data,
+                meta: entry.meta,
+            };
+            self.index(&record);
+            self.records.insert(entry.id, record);
+        }
+    }
+
+    /// Build the entries for new writes of records, stamped with the next sequence number, the
+    /// current time and the configured source.
+    ///
+    /// The entries take consecutive sequence numbers after the highest one applied so far, which
+    /// are only used up once the entries are applied.
+    fn stamp(&self, entries: Vec<(UniqueId, Option<T>)>) -> Vec<DeletableRecord<T>> {
+        // Times are stored to the second, so truncate here to match what a reload sees.
+        let written = DateTimeTz::now().map(|t| t.with_nanosecond(0).unwrap_or(t));
+        entries
+            .into_iter()
+            .zip(self.seq + 1..)
+            .map(|((id, data), seq)| DeletableRecord {
+                id,
+                data,
+                meta: Metadata {
+                    seq: Some(seq),
+                    written: Some(written.clone()),
+                    source: self.options.source.clone(),
+                    schema: self.current_schema(),
+                },
+            })
+            .collect()
+    }
+
+    /// Write entries to the file in a single append, and only once the write has succeeded apply
+    /// them to the in-memory view, followed by the retention and compaction policies. With
+    /// `transaction` set, the entries are bracketed by begin and commit markers.
+    fn write_and_apply(
+        &mut self,
+        entries: Vec<DeletableRecord<T>>,
+        transaction: bool,
+    ) -> Result<(), Error> {
+        let mut lines = Vec::with_capacity(entries.len() + 2);
+        let id = UniqueId::new();
+        if transaction {
+            lines.push(
+                serde_json::to_string(&Marker::Begin(id.clone()))
+                    .map_err(Error::JSONStringError)?,
+            );
+        }
+        for entry in entries.iter() {
+            lines.push(serde_json::to_string(entry).map_err(Error::JSONStringError)?);
+        }
+        if transaction {
+            lines.push(serde_json::to_string(&Marker::Commit(id)).map_err(Error::JSONStringError)?);
+        }
+        self.write_entries(&lines)?;
+
+        for entry in entries {
+            self.apply(entry);
+        }
+        self.drop_expired();
+        self.maybe_compact()
+    }
+
+    /// Whether any live record was written with an older version of the record type.
+    fn has_migrated(&self) -> bool {
+        let current = self.options.migrations.version();
+        self.records
+            .values()
+            .any(|record| record.meta.schema.unwrap_or(0) < current)
+    }
+
+    /// Write a header line describing a new file, which must be empty.
+    fn write_header(&mut self) -> Result<(), Error> {
+        let header = Header {
+            format: FORMAT_VERSION,
+            type_name: Some(String::from(std::any::type_name::<T>())),
+            created: Some(DateTimeTz::now()),
+            checksums: self.options.checksums,
+            compression: None,
+        };
+        self.write_entries(&[header_line(&header)?])?;
+        self.header = Some(header);
+        Ok(())
+    }
+
+    /// The options that the series was opened with, adjusted to match the file's header.
+    pub fn options(&self) -> &SeriesOptions {
+        &self.options
+    }
+
+    /// The header of the series file, if it has one.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// The record type version to write with new entries, or None for version 0 so that files
+    /// without migrations keep the legacy format.
+    fn current_schema(&self) -> Option<u32> {
+        match self.options.migrations.version() {
+            0 => None,
+            version => Some(version),
+        }
+    }
+
+    /// Add a record to the time and tag indexes.
+    fn index(&mut self, record: &Record<T>) {
+        self.time_index
+            .insert(record.timestamp(), record.id.clone());
+        self.tag_index.insert(record.tags(), &record.id);
+    }
+
+    /// Remove a record from the time and tag indexes.
+    fn unindex(&mut self, record: &Record<T>) {
+        self.time_index.remove(&record.timestamp(), &record.id);
+        self.tag_index.remove(record.tags(), &record.id);
+    }
+
+    /// Append several serialized entries to the file with a single write, and apply the
+    /// durability policy. Compaction is left to the caller, since it rewrites the file from
+    /// the records in memory.
+    fn write_entries(&mut self, entries: &[String]) -> Result<(), Error> {
+        let mut buf = String::new();
+        for entry in entries {
+            buf.push_str(&frame::encode(entry, self.options.checksums));
+            buf.push('\n');
+        }
+        let res = self
+            .writer
+            .as_mut()
+            .ok_or(Error::ReadOnly)?
+            .write_all(buf.as_bytes());
+        if let Err(err) = res {
+            self.abandon_write()?;
+            return Err(Error::IOError(err));
+        }
+        self.lines += entries.len();
+        self.offset += buf.len() as u64;
+        self.unsynced += 1;
+        self.maybe_sync()
+    }
+
+    /// Undo a failed append, part of which may have reached the file, by cutting the file back to
+    /// the end of the last complete write and starting a new writer.
+    fn abandon_write(&mut self) -> Result<(), Error> {
+        // Dropping the writer may flush whatever it still buffers, so truncate after it is gone.
+        drop(self.writer.take());
+        self.file.set_len(self.offset).map_err(Error::IOError)?;
+        self.writer = Some(LineWriter::new(
+            self.file.try_clone().map_err(Error::IOError)?,
+        ));
+        Ok(())
+    }
+
+    /// Sync the file if the durability policy says that it is time to do so.
+    fn maybe_sync(&mut self) -> Result<(), Error> {
+        let due = match self.options.durability {
+            Durability::Never => false,
+            Durability::EveryWrite => true,
+            Durability::EveryNWrites(n) => self.unsynced >= n,
+            Durability::Interval(interval) => interval
+                .to_std()
+                .map(|interval| self.last_sync.elapsed() >= interval)
+                .unwrap_or(true),
+        };
+        if due {
+            self.flush_and_sync()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Flush any buffered writes and sync the file's data to disk, so that every write made so far
+    /// survives a power failure.
+    pub fn flush_and_sync(&mut self) -> Result<(), Error> {
+        self.writer
+            .as_mut()
+            .ok_or(Error::ReadOnly)?
+            .flush()
+            .map_err(Error::IOError)?;
+        self.file.sync_data().map_err(Error::IOError)?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Remove records that have fallen out of the retention window from memory, without writing
+    /// anything to the file. They remain in the file as dead lines until the next compaction.
+    fn drop_expired(&mut self) {
+        if let Some(retention) = self.options.retention {
+            let cutoff = DateTimeTz::now().map(|now| now - retention);
+            for id in self.ids_before(&cutoff) {
+                if let Some(old) = self.records.remove(&id) {
+                    self.unindex(&old);
                 }
-                Err(err) => return Err(Error::IOError(err)),
             }
         }
-        Ok(records)
+    }
+
+    /// The ids of all records with timestamps strictly before `time`.
+    fn ids_before(&self, time: &DateTimeTz) -> Vec<UniqueId> {
+        self.time_index
+            .range(&TimeBounds {
+                start: Bound::Unbounded,
+                end: Bound::Excluded(time.clone()),
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Delete every record with a timestamp strictly before `time`, returning the number of
+    /// records deleted. The deletions are written to the file in a single append.
+    pub fn expire_before(&mut self, time: &DateTimeTz) -> Result<usize, Error> {
+        let ids = self.ids_before(time);
+        let count = ids.len();
+        let entries = self.stamp(ids.into_iter().map(|id| (id, None)).collect());
+        self.write_and_apply(entries, false)?;
+        Ok(count)
+    }
+
+    /// Compact the file if the compaction policy says that it is time to do so.
+    fn maybe_compact(&mut self) -> Result<(), Error> {
+        if self.writer.is_some()
+            && self
+                .options
+                .compaction
+                .should_compact(self.lines, self.records.len())
+        {
+            self.compact()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Rewrite the series file so that it contains only the live records.
+    ///
+    /// The new file is written to a temporary file in the same directory and then renamed over the
+    /// original, so a crash part way through leaves the original file intact. Unless the
+    /// `durability` option is `Never`, the directory is synced after the rename. Note that this
+    /// discards the history of updated and deleted records, and drops any records that have
+    /// fallen out of the retention window.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        self.writer
+            .as_mut()
+            .ok_or(Error::ReadOnly)?
+            .flush()
+            .map_err(Error::IOError)?;
+        self.drop_expired();
+
+        let path = Path::new(&self.path);
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut tmp = tempfile::NamedTempFile::new_in(dir).map_err(Error::IOError)?;
+        {
+            let mut w = BufWriter::new(tmp.as_file_mut());
+            if let Some(ref header) = self.header {
+                w.write_fmt(format_args!(
+                    "{}\n",
+                    frame::encode(&header_line(header)?, self.options.checksums)
+                ))
+                .map_err(Error::IOError)?;
+            }
+            for record in self
+                .time_index
+                .range(&TimeBounds::unbounded())
+                .filter_map(|id| self.records.get(id))
+            {
+                let rec = DeletableRecord {
+                    id: record.id.clone(),
+                    data: Some(record.data.clone()),
+                    // The data has been upgraded as it was loaded, so it is rewritten in the
+                    // current version.
+                    meta: Metadata {
+                        schema: self.current_schema(),
+                        ..record.meta.clone()
+                    },
+                };
+                let rec_str = serde_json::to_string(&rec).map_err(Error::JSONStringError)?;
+                w.write_fmt(format_args!(
+                    "{}\n",
+                    frame::encode(&rec_str, self.options.checksums)
+                ))
+                .map_err(Error::IOError)?;
+            }
+            w.flush().map_err(Error::IOError)?;
+        }
+        tmp.as_file().sync_all().map_err(Error::IOError)?;
+
+        let permissions = fs::metadata(path).map_err(Error::IOError)?.permissions();
+        fs::set_permissions(tmp.path(), permissions).map_err(Error::IOError)?;
+        // Lock the new file before it becomes visible at the path, so that there is never an
+        // unlocked file there for another process to open.
+        if self.options.lock {
+            tmp.as_file().try_lock_exclusive().map_err(lock_error)?;
+        }
+        // The handle is positioned at the end of the new file, so further writes through it
+        // append.
+        let f = tmp.persist(path).map_err(|err| Error::IOError(err.error))?;
+        // Writes since the last sync are only in the new file, so the rename has to be durable
+        // too before they count as synced.
+        if self.options.durability != Durability::Never {
+            sync_dir(dir)?;
+        }
+
+        self.writer = Some(LineWriter::new(f.try_clone().map_err(Error::IOError)?));
+        self.offset = f.metadata().map_err(Error::IOError)?.len();
+        self.file = f;
+        self.lines = self.records.len() + self.header.iter().count();
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
     }
 
     /// Put a new record into the database. A unique id will be assigned to the record and
     /// returned.
+    ///
+    /// By the time the id is returned the record has been written to the file, and synced to disk
+    /// if the `durability` option calls for a sync on this write.
     pub fn put(&mut self, entry: T) -> Result<UniqueId, Error> {
         let record = Record::new(entry);
         let rec_id = record.id.clone();
@@ -86,40 +916,69 @@ where
     /// Update an existing record. The `UniqueId` of the record passed into this function must match
     /// the `UniqueId` of a record already in the database.
     pub fn update(&mut self, record: Record<T>) -> Result<(), Error> {
-        self.records.insert(record.id.clone(), record.clone());
-        let write_res = match serde_json::to_string(&record) {
-            Ok(rec_str) => self
-                .writer
-                .write_fmt(format_args!("{}\n", rec_str.as_str()))
-                .map_err(Error::IOError),
-            Err(err) => Err(Error::JSONStringError(err)),
-        };
+        let entries = self.stamp(vec![(record.id, Some(record.data))]);
+        self.write_and_apply(entries, false)
+    }
 
-        match write_res {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err),
+    /// Put several new records into the database with a single append to the file, returning
+    /// their newly assigned ids in the same order. The records become visible only once the
+    /// write has succeeded; if it fails, none of them are added.
+    pub fn put_batch<I>(&mut self, entries: I) -> Result<Vec<UniqueId>, Error>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let records: Vec<Record<T>> = entries.into_iter().map(Record::new).collect();
+        let ids = records.iter().map(|record| record.id.clone()).collect();
+        self.update_batch(records)?;
+        Ok(ids)
+    }
+
+    /// Update several records with a single append to the file. As with `update`, each record's
+    /// `UniqueId` should match a record already in the database. The in-memory view changes
+    /// only once the write has succeeded; if it fails, none of the updates are applied.
+    pub fn update_batch<I>(&mut self, records: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = Record<T>>,
+    {
+        let entries = self.stamp(
+            records
+                .into_iter()
+                .map(|record| (record.id, Some(record.data)))
+                .collect(),
+        );
+        self.write_and_apply(entries, false)
+    }
+
+    /// Start a transaction. Changes made through the transaction are written to the file together
+    /// when it is committed, and take effect all at once: if the process dies part way through
+    /// the write, none of them are seen when the series is next opened.
+    pub fn begin(&mut self) -> Transaction<'_, T> {
+        Transaction::new(self)
+    }
+
+    /// Write the entries of a committed transaction between begin and commit markers, in a single
+    /// append, and then apply them to the in-memory view.
+    pub(crate) fn commit_entries(&mut self, entries: Vec<DeletableRecord<T>>) -> Result<(), Error> {
+        if entries.is_empty() {
+            return Ok(());
         }
+        let entries = self.stamp(
+            entries
+                .into_iter()
+                .map(|entry| (entry.id, entry.data))
+                .collect(),
+        );
+        self.write_and_apply(entries, true)
     }
 
     /// Delete a record from the database
     ///
-    /// Future note: while this deletes a record from the view, it only adds an entry to the
-    /// database that indicates `data: null`. If record histories ever become important, the record
-    /// and its entire history (including this delete) will still be available.
+    /// While this deletes a record from the view, it only adds an entry to the database that
+    /// indicates `data: null`. The record and its entire history, including this delete, remain
+    /// available through `history` until the file is compacted.
     pub fn delete(&mut self, uuid: &UniqueId) -> Result<(), Error> {
-        self.records.remove(uuid);
-
-        let rec: DeletableRecord<T> = DeletableRecord {
-            id: uuid.clone(),
-            data: None,
-        };
-        match serde_json::to_string(&rec) {
-            Ok(rec_str) => self
-                .writer
-                .write_fmt(format_args!("{}\n", rec_str.as_str()))
-                .map_err(Error::IOError),
-            Err(err) => Err(Error::JSONStringError(err)),
-        }
+        let entries = self.stamp(vec![(uuid.clone(), None)]);
+        self.write_and_apply(entries, false)
     }
 
     /// Get all of the records in the database.
@@ -135,19 +994,109 @@ where
     /*  The point of having Search is so that a lot of internal optimizations can happen once the
      *  data sets start getting large. */
     /// Perform a search on the records in a database, based on the given criteria.
+    ///
+    /// Only the records within the time bounds of the criteria, or carrying the tags required by
+    /// the criteria, are visited. The results are returned in timestamp order.
     pub fn search<C>(&self, criteria: C) -> Result<Vec<Record<T>>, Error>
     where
         C: Criteria,
     {
         let results: Vec<Record<T>> = self
-            .records
-            .iter()
-            .filter(|&tr| criteria.apply(tr.1))
-            .map(|tr| tr.1.clone())
+            .matching(&criteria, TimeBounds::unbounded())
+            .cloned()
+            .collect();
+        Ok(results)
+    }
+
+    /// Perform a search, returning one page of the results.
+    ///
+    /// Results are ordered by timestamp, and then by id for records that share a timestamp, in the
+    /// direction given by `options.order`. If `options.after` is set, only records strictly after
+    /// that cursor in the chosen order are returned, which gives stable keyset pagination: pass
+    /// the cursor of the last record of one page to get the next page.
+    pub fn search_with_options<C>(
+        &self,
+        criteria: C,
+        options: &QueryOptions,
+    ) -> Result<Vec<Record<T>>, Error>
+    where
+        C: Criteria,
+    {
+        let after = options.after.clone();
+        let bounds = match (&after, options.order) {
+            (None, _) => TimeBounds::unbounded(),
+            (Some(cursor), Order::Ascending) => TimeBounds {
+                start: Bound::Included(cursor.timestamp.clone()),
+                end: Bound::Unbounded,
+            },
+            (Some(cursor), Order::Descending) => TimeBounds {
+                start: Bound::Unbounded,
+                end: Bound::Included(cursor.timestamp.clone()),
+            },
+        };
+
+        let matches = self.matching(&criteria, bounds);
+        let ordered: Box<dyn Iterator<Item = &Record<T>>> = match options.order {
+            Order::Ascending => Box::new(matches),
+            Order::Descending => Box::new(matches.rev()),
+        };
+        let results = ordered
+            .filter(|record| match &after {
+                None => true,
+                Some(cursor) => {
+                    let position = (record.timestamp(), &record.id);
+                    let cursor = (cursor.timestamp.clone(), &cursor.id);
+                    match options.order {
+                        Order::Ascending => position > cursor,
+                        Order::Descending => position < cursor,
+                    }
+                }
+            })
+            .skip(options.offset)
+            .take(options.limit.unwrap_or(usize::MAX))
+            .cloned()
             .collect();
         Ok(results)
     }
 
+    /// Find all of the records that match the criteria and fall within the extra time bounds,
+    /// using whichever index the criteria allows. Records are returned in timestamp order, and in
+    /// id order for records that share a timestamp.
+    fn matching<'s, C>(
+        &'s self,
+        criteria: &'s C,
+        bounds: TimeBounds,
+    ) -> Box<dyn DoubleEndedIterator<Item = &'s Record<T>> + 's>
+    where
+        C: Criteria,
+    {
+        let tags = criteria.required_tags();
+        if tags.is_empty() {
+            let bounds = criteria.time_bounds().intersect(bounds);
+            Box::new(
+                self.time_index
+                    .range(&bounds)
+                    .filter_map(move |id| self.records.get(id))
+                    .filter(move |&record| criteria.apply(&record.data)),
+            )
+        } else {
+            let mut results: Vec<&Record<T>> = self
+                .tag_index
+                .intersect(&tags)
+                .iter()
+                .filter_map(|id| self.records.get(id))
+                .filter(|&record| bounds.contains(&record.timestamp()))
+                .filter(|&record| criteria.apply(&record.data))
+                .collect();
+            results.sort_by(|l, r| {
+                l.timestamp()
+                    .cmp(&r.timestamp())
+                    .then_with(|| l.id.cmp(&r.id))
+            });
+            Box::new(results.into_iter())
+        }
+    }
+
     /// Perform a search and sort the resulting records based on the comparison.
     pub fn search_sorted<C, CMP>(&self, criteria: C, compare: CMP) -> Result<Vec<Record<T>>, Error>
     where
@@ -163,22 +1112,59 @@ where
         }
     }
 
+    /// Group the records that match the criteria into buckets by timestamp, and reduce each bucket
+    /// to a single value. `extract` pulls the value to be reduced out of each record. The result
+    /// holds the start time of each non-empty bucket along with its value, in time order.
+    pub fn aggregate<C, F>(
+        &self,
+        criteria: C,
+        bucket: Bucket,
+        reducer: Reducer,
+        extract: F,
+    ) -> Result<Vec<(DateTimeTz, f64)>, Error>
+    where
+        C: Criteria,
+        F: Fn(&T) -> f64,
+    {
+        let values = self
+            .matching(&criteria, TimeBounds::unbounded())
+            .map(|record| (record.timestamp(), extract(&record.data)));
+        Ok(summarize(&bucket, values)
+            .into_iter()
+            .map(|(start, summary)| (start, summary.reduce(reducer)))
+            .collect())
+    }
+
     /// Get an exact record from the database based on unique id.
     pub fn get(&self, uuid: &UniqueId) -> Result<Option<Record<T>>, Error> {
         let val = self.records.get(uuid);
         Ok(val.cloned())
     }
 
-    /// Get a subset of records from the database based on a predicate.
-    pub fn filter(&self, predicate: fn(&T) -> bool) -> Result<Vec<Record<T>>, Error> {
-        let mut ret: Vec<Record<T>> = Vec::new();
+    /// Get a subset of records from the database based on a predicate. The predicate may be any
+    /// closure, so it can capture thresholds or other state from the caller.
+    pub fn filter<F>(&self, predicate: F) -> Result<Vec<Record<T>>, Error>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let results = self.filter_iter(predicate)?.cloned().collect();
+        Ok(results)
+    }
 
-        for record in self.records.clone().into_values() {
-            if predicate(&record.data) {
-                ret.push(record);
-            }
-        }
-        Ok(ret)
+    /// Lazily iterate, in timestamp order, over the records that match a predicate. Unlike
+    /// `filter`, this borrows the records rather than cloning them.
+    pub fn filter_iter<'s, F>(
+        &'s self,
+        predicate: F,
+    ) -> Result<impl Iterator<Item = &'s Record<T>> + 's, Error>
+    where
+        F: Fn(&T) -> bool + 's,
+    {
+        Ok(self
+            .time_index
+            .range(&TimeBounds::unbounded())
+            .filter_map(move |id| self.records.get(id))
+            .filter(move |record| predicate(&record.data)))
     }
 
     /*
@@ -199,7 +1185,11 @@ mod tests {
     use chrono_tz::Etc::UTC;
 
     use super::*;
+    use crate::aggregate::CalendarUnit;
     use crate::criteria::*;
+    use crate::options::{
+        Access, Compaction, Durability, Migrations, Order, QueryOptions, TornWrite,
+    };
 
     #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
     struct Distance(Meter<f64>);
@@ -259,6 +1249,42 @@ mod tests {
         ]
     }
 
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    struct Workout {
+        datetime: DateTimeTz,
+        tags: Vec<String>,
+    }
+
+    impl Recordable for Workout {
+        fn timestamp(&self) -> DateTimeTz {
+            self.datetime.clone()
+        }
+        fn tags(&self) -> Vec<String> {
+            self.tags.clone()
+        }
+    }
+
+    fn mk_workouts() -> [Workout; 4] {
+        [
+            Workout {
+                datetime: DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 1, 0, 0, 0).unwrap()),
+                tags: vec![String::from("run"), String::from("outdoor")],
+            },
+            Workout {
+                datetime: DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 2, 0, 0, 0).unwrap()),
+                tags: vec![String::from("run"), String::from("treadmill")],
+            },
+            Workout {
+                datetime: DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 3, 0, 0, 0).unwrap()),
+                tags: vec![String::from("bike"), String::from("outdoor")],
+            },
+            Workout {
+                datetime: DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 4, 0, 0, 0).unwrap()),
+                tags: vec![String::from("run"), String::from("outdoor")],
+            },
+        ]
+    }
+
     fn run_test<T>(test: T) -> ()
     where
         T: FnOnce(tempfile::TempPath),
@@ -401,14 +1427,43 @@ mod tests {
     }
 
     #[test]
-    pub fn persists_and_reads_an_entry() {
+    pub fn can_filter_with_a_closure() {
         run_test(|path| {
             let trips = mk_trips();
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+
+            for trip in &trips[0..=4] {
+                ts.put(trip.clone()).expect("expect a successful put");
+            }
+
+            let threshold = Distance(30000.0 * M);
+            let v = ts
+                .filter(|b| b.distance.0 > threshold.0)
+                .expect("filter should succeed");
+            assert_eq!(v.len(), 3);
+            assert_eq!(v[0].data, trips[0]);
+            assert_eq!(v[1].data, trips[2]);
+            assert_eq!(v[2].data, trips[3]);
+
+            let mut iter = ts
+                .filter_iter(|b| b.distance.0 < threshold.0)
+                .expect("filter should succeed");
+            assert_eq!(iter.next().map(|r| &r.data), Some(&trips[1]));
+            assert_eq!(iter.next().map(|r| &r.data), Some(&trips[4]));
+            assert!(iter.next().is_none());
+        })
+    }
+
+    #[test]
+    pub fn persists_and_reads_an_entry() {
+        run_test(|path| {
+            let trips = mk_trips();
+
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
 
-            {
-                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
-                    .expect("expect the time series to open correctly");
-
                 for trip in &trips[0..=4] {
                     ts.put(trip.clone()).expect("expect a successful put");
                 }
@@ -615,38 +1670,1582 @@ mod tests {
         })
     }
 
-    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-    pub struct Weight(Kilogram<f64>);
+    #[test]
+    pub fn search_results_are_in_time_order() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
 
-    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-    pub struct WeightRecord {
-        pub date: DateTimeTz,
-        pub weight: Weight,
+            for trip in trips.iter().rev() {
+                ts.put(trip.clone()).expect("expect a successful put");
+            }
+
+            let v = ts
+                .search(time_range(
+                    DateTimeTz(UTC.with_ymd_and_hms(2011, 10, 31, 0, 0, 0).unwrap()),
+                    false,
+                    DateTimeTz(UTC.with_ymd_and_hms(2011, 11, 5, 0, 0, 0).unwrap()),
+                    true,
+                ))
+                .expect("search should succeed");
+            assert_eq!(v.len(), 3);
+            assert_eq!(v[0].data, trips[2]);
+            assert_eq!(v[1].data, trips[3]);
+            assert_eq!(v[2].data, trips[4]);
+
+            let empty = ts
+                .search(time_range(
+                    DateTimeTz(UTC.with_ymd_and_hms(2011, 11, 4, 0, 0, 0).unwrap()),
+                    false,
+                    DateTimeTz(UTC.with_ymd_and_hms(2011, 11, 2, 0, 0, 0).unwrap()),
+                    true,
+                ))
+                .expect("search should succeed");
+            assert_eq!(empty.len(), 0);
+        })
     }
 
-    impl Recordable for WeightRecord {
-        fn timestamp(&self) -> DateTimeTz {
-            self.date.clone()
-        }
+    #[test]
+    pub fn time_index_follows_updates_and_deletes() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
 
-        fn tags(&self) -> Vec<String> {
-            Vec::new()
-        }
+            let first_id = ts.put(trips[0].clone()).expect("expect a successful put");
+            let second_id = ts.put(trips[1].clone()).expect("expect a successful put");
+
+            let mut moved = ts.get(&first_id).unwrap().unwrap();
+            moved.data.datetime = DateTimeTz(UTC.with_ymd_and_hms(2011, 11, 2, 0, 0, 0).unwrap());
+            ts.update(moved).expect("expect record to update");
+            ts.delete(&second_id).expect("successful delete");
+
+            let old_time = ts
+                .search(exact_time(DateTimeTz(
+                    UTC.with_ymd_and_hms(2011, 10, 29, 0, 0, 0).unwrap(),
+                )))
+                .expect("search should succeed");
+            assert_eq!(old_time.len(), 0);
+
+            let new_time = ts
+                .search(exact_time(DateTimeTz(
+                    UTC.with_ymd_and_hms(2011, 11, 2, 0, 0, 0).unwrap(),
+                )))
+                .expect("search should succeed");
+            assert_eq!(new_time.len(), 1);
+            assert_eq!(new_time[0].id, first_id);
+
+            let all = ts
+                .search(time_range(
+                    DateTimeTz(UTC.with_ymd_and_hms(2011, 10, 1, 0, 0, 0).unwrap()),
+                    true,
+                    DateTimeTz(UTC.with_ymd_and_hms(2011, 12, 1, 0, 0, 0).unwrap()),
+                    true,
+                ))
+                .expect("search should succeed");
+            assert_eq!(all.len(), 1);
+        })
     }
 
     #[test]
-    pub fn legacy_file_load() {
-        let ts: Series<WeightRecord> =
-            Series::open("fixtures/weight.json").expect("legacy series should open correctly");
+    pub fn can_search_by_tags() {
+        run_test(|path| {
+            let workouts = mk_workouts();
+            let mut ts: Series<Workout> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
 
-        let uid = "3330c5b0-783f-4919-b2c4-8169c38f65ff"
-            .parse()
-            .expect("something is wrong with this ID");
-        let rec = ts.get(&uid);
-        match rec {
-            Err(err) => assert!(false, "{}", err),
-            Ok(None) => assert!(false, "no record found"),
-            Ok(Some(rec)) => assert_eq!(rec.data.weight, Weight(77.79109 * KG)),
-        }
+            for workout in workouts.iter().rev() {
+                ts.put(workout.clone()).expect("expect a successful put");
+            }
+
+            let v = ts
+                .search(Tags {
+                    tags: vec![String::from("run"), String::from("outdoor")],
+                })
+                .expect("search should succeed");
+            assert_eq!(v.len(), 2);
+            assert_eq!(v[0].data, workouts[0]);
+            assert_eq!(v[1].data, workouts[3]);
+
+            let v = ts
+                .search(And {
+                    lside: Tags {
+                        tags: vec![String::from("outdoor")],
+                    },
+                    rside: StartTime {
+                        time: DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 2, 0, 0, 0).unwrap()),
+                        incl: true,
+                    },
+                })
+                .expect("search should succeed");
+            assert_eq!(v.len(), 2);
+            assert_eq!(v[0].data, workouts[2]);
+            assert_eq!(v[1].data, workouts[3]);
+
+            let v = ts
+                .search(Tags {
+                    tags: vec![String::from("swim")],
+                })
+                .expect("search should succeed");
+            assert_eq!(v.len(), 0);
+        })
+    }
+
+    #[test]
+    pub fn tag_index_follows_updates_and_deletes() {
+        run_test(|path| {
+            let workouts = mk_workouts();
+            let mut ts: Series<Workout> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+
+            let first_id = ts
+                .put(workouts[0].clone())
+                .expect("expect a successful put");
+            let second_id = ts
+                .put(workouts[1].clone())
+                .expect("expect a successful put");
+
+            let mut retagged = ts.get(&first_id).unwrap().unwrap();
+            retagged.data.tags = vec![String::from("swim")];
+            ts.update(retagged).expect("expect record to update");
+            ts.delete(&second_id).expect("successful delete");
+
+            let runs = ts
+                .search(Tags {
+                    tags: vec![String::from("run")],
+                })
+                .expect("search should succeed");
+            assert_eq!(runs.len(), 0);
+
+            let swims = ts
+                .search(Tags {
+                    tags: vec![String::from("swim")],
+                })
+                .expect("search should succeed");
+            assert_eq!(swims.len(), 1);
+            assert_eq!(swims[0].id, first_id);
+        })
+    }
+
+    #[test]
+    pub fn can_search_with_composed_criteria() {
+        run_test(|path| {
+            let workouts = mk_workouts();
+            let mut ts: Series<Workout> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+
+            for workout in workouts.iter() {
+                ts.put(workout.clone()).expect("expect a successful put");
+            }
+
+            let v = ts
+                .search(
+                    time_range(
+                        DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 1, 0, 0, 0).unwrap()),
+                        true,
+                        DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 4, 0, 0, 0).unwrap()),
+                        false,
+                    )
+                    .and(
+                        Tags {
+                            tags: vec![String::from("run")],
+                        }
+                        .or(Tags {
+                            tags: vec![String::from("bike")],
+                        }),
+                    )
+                    .and(
+                        Tags {
+                            tags: vec![String::from("treadmill")],
+                        }
+                        .not(),
+                    ),
+                )
+                .expect("search should succeed");
+            assert_eq!(v.len(), 2);
+            assert_eq!(v[0].data, workouts[0]);
+            assert_eq!(v[1].data, workouts[2]);
+
+            let v = ts
+                .search(
+                    exact_time(DateTimeTz(
+                        UTC.with_ymd_and_hms(2019, 5, 1, 0, 0, 0).unwrap(),
+                    ))
+                    .or(exact_time(DateTimeTz(
+                        UTC.with_ymd_and_hms(2019, 5, 4, 0, 0, 0).unwrap(),
+                    ))),
+                )
+                .expect("search should succeed");
+            assert_eq!(v.len(), 2);
+            assert_eq!(v[0].data, workouts[0]);
+            assert_eq!(v[1].data, workouts[3]);
+        })
+    }
+
+    #[test]
+    pub fn can_search_with_runtime_criteria() {
+        run_test(|path| {
+            let workouts = mk_workouts();
+            let mut ts: Series<Workout> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+
+            for workout in workouts.iter() {
+                ts.put(workout.clone()).expect("expect a successful put");
+            }
+
+            let criteria = DynCriteria::AllOf(vec![
+                DynCriteria::StartTime(StartTime {
+                    time: DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 1, 0, 0, 0).unwrap()),
+                    incl: true,
+                }),
+                DynCriteria::EndTime(EndTime {
+                    time: DateTimeTz(UTC.with_ymd_and_hms(2019, 5, 4, 0, 0, 0).unwrap()),
+                    incl: false,
+                }),
+                DynCriteria::AnyOf(vec![
+                    DynCriteria::Tags(Tags {
+                        tags: vec![String::from("run")],
+                    }),
+                    DynCriteria::Tags(Tags {
+                        tags: vec![String::from("bike")],
+                    }),
+                ]),
+                DynCriteria::Not(Box::new(DynCriteria::Tags(Tags {
+                    tags: vec![String::from("treadmill")],
+                }))),
+            ]);
+
+            let as_json = serde_json::to_string(&criteria).expect("criteria should serialize");
+            let parsed: DynCriteria =
+                serde_json::from_str(&as_json).expect("criteria should deserialize");
+            assert_eq!(parsed, criteria);
+
+            let v = ts.search(parsed).expect("search should succeed");
+            assert_eq!(v.len(), 2);
+            assert_eq!(v[0].data, workouts[0]);
+            assert_eq!(v[1].data, workouts[2]);
+
+            let v = ts
+                .search(DynCriteria::AnyOf(Vec::new()))
+                .expect("search should succeed");
+            assert_eq!(v.len(), 0);
+        })
+    }
+
+    #[test]
+    pub fn can_search_on_record_fields() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+
+            for trip in &trips[0..=4] {
+                ts.put(trip.clone()).expect("expect a successful put");
+            }
+
+            let criteria: DynCriteria = serde_json::from_str(
+                "{\"AllOf\":[{\"Field\":{\"path\":\"/distance\",\"op\":{\"Gt\":30000}}},
+                 {\"Not\":{\"Field\":{\"path\":\"/comments\",\"op\":{\"Matches\":\"^Do\"}}}}]}",
+            )
+            .expect("criteria should deserialize");
+            let v = ts.search(criteria).expect("search should succeed");
+            assert_eq!(v.len(), 2);
+            assert_eq!(v[0].data, trips[0]);
+            assert_eq!(v[1].data, trips[3]);
+        })
+    }
+
+    #[test]
+    pub fn can_page_through_search_results() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+
+            for trip in &trips[0..=4] {
+                ts.put(trip.clone()).expect("expect a successful put");
+            }
+            let twin = ts.put(trips[2].clone()).expect("expect a successful put");
+
+            let mut options = QueryOptions {
+                limit: Some(2),
+                ..QueryOptions::default()
+            };
+            let mut pages = Vec::new();
+            loop {
+                let page = ts
+                    .search_with_options(
+                        StartTime {
+                            time: DateTimeTz(UTC.with_ymd_and_hms(2011, 10, 30, 0, 0, 0).unwrap()),
+                            incl: true,
+                        },
+                        &options,
+                    )
+                    .expect("search should succeed");
+                match page.last() {
+                    None => break,
+                    Some(last) => options.after = Some(last.cursor()),
+                }
+                pages.push(page);
+            }
+            assert_eq!(pages.len(), 3);
+            assert_eq!(pages[0][0].data, trips[1]);
+            assert_eq!(pages[0][1].data, trips[2]);
+            assert_eq!(pages[1][0].data, trips[2]);
+            assert_eq!(pages[1][1].data, trips[3]);
+            assert_eq!(pages[2].len(), 1);
+            assert_eq!(pages[2][0].data, trips[4]);
+            assert!(pages[0][1].id == twin || pages[1][0].id == twin);
+
+            let page = ts
+                .search_with_options(
+                    Tags { tags: Vec::new() },
+                    &QueryOptions {
+                        order: Order::Descending,
+                        offset: 1,
+                        limit: Some(2),
+                        ..QueryOptions::default()
+                    },
+                )
+                .expect("search should succeed");
+            assert_eq!(page.len(), 2);
+            assert_eq!(page[0].data, trips[3]);
+            assert_eq!(page[1].data, trips[2]);
+
+            let page = ts
+                .search_with_options(
+                    Tags { tags: Vec::new() },
+                    &QueryOptions {
+                        order: Order::Descending,
+                        after: Some(page[1].cursor()),
+                        ..QueryOptions::default()
+                    },
+                )
+                .expect("search should succeed");
+            assert_eq!(page.len(), 3);
+            assert_eq!(page[0].data, trips[2]);
+            assert_eq!(page[1].data, trips[1]);
+            assert_eq!(page[2].data, trips[0]);
+        })
+    }
+
+    #[test]
+    pub fn can_aggregate_into_buckets() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+
+            for trip in &trips[0..=4] {
+                ts.put(trip.clone()).expect("expect a successful put");
+            }
+
+            let weekly = ts
+                .aggregate(
+                    StartTime {
+                        time: DateTimeTz(UTC.with_ymd_and_hms(2011, 10, 29, 0, 0, 0).unwrap()),
+                        incl: true,
+                    },
+                    Bucket::Calendar(CalendarUnit::Week),
+                    Reducer::Sum,
+                    |trip| trip.distance.0.value_unsafe,
+                )
+                .expect("aggregation should succeed");
+            assert_eq!(
+                weekly,
+                vec![
+                    (
+                        DateTimeTz(UTC.with_ymd_and_hms(2011, 10, 24, 0, 0, 0).unwrap()),
+                        58741.055
+                    ),
+                    (
+                        DateTimeTz(UTC.with_ymd_and_hms(2011, 10, 31, 0, 0, 0).unwrap()),
+                        17702.0 + 41842.945 + 34600.895 + 6437.376
+                    ),
+                ]
+            );
+
+            let counts = ts
+                .aggregate(
+                    Tags { tags: Vec::new() },
+                    Bucket::Fixed(chrono::Duration::days(3)),
+                    Reducer::Count,
+                    |_| 0.0,
+                )
+                .expect("aggregation should succeed");
+            assert_eq!(counts.iter().map(|(_, c)| *c).sum::<f64>(), 5.0);
+        })
+    }
+
+    fn count_lines(path: &tempfile::TempPath) -> usize {
+        fs::read_to_string(path)
+            .expect("the series file should be readable")
+            .lines()
+            .count()
+    }
+
+    #[test]
+    pub fn compaction_drops_dead_lines() {
+        run_test(|path| {
+            let trips = mk_trips();
+
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                let trip_id = ts.put(trips[0].clone()).expect("expect a successful put");
+                ts.put(trips[1].clone()).expect("expect a successful put");
+                let mut trip = ts.get(&trip_id).unwrap().unwrap();
+                trip.data.distance = Distance(50000.0 * M);
+                ts.update(trip).expect("expect record to update");
+                let del_id = ts.put(trips[2].clone()).expect("expect a successful put");
+                ts.delete(&del_id).expect("successful delete");
+                assert_eq!(count_lines(&path), 5);
+
+                ts.compact().expect("compaction should succeed");
+                assert_eq!(count_lines(&path), 2);
+
+                ts.put(trips[3].clone()).expect("expect a successful put");
+                assert_eq!(count_lines(&path), 3);
+            }
+
+            {
+                let ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                let mut recs = ts.all_records().expect("good record retrieval");
+                recs.sort_by_key(|r| r.timestamp());
+                assert_eq!(recs.len(), 3);
+                assert_eq!(recs[0].data.distance, Distance(50000.0 * M));
+                assert_eq!(recs[1].data, trips[1]);
+                assert_eq!(recs[2].data, trips[3]);
+            }
+        })
+    }
+
+    #[test]
+    pub fn compaction_policy_applies_on_open_and_write() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let options = SeriesOptions {
+                compaction: Compaction::DeadRatio {
+                    ratio: 0.5,
+                    min_lines: 4,
+                },
+                ..SeriesOptions::default()
+            };
+
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                let trip_id = ts.put(trips[0].clone()).expect("expect a successful put");
+                ts.put(trips[1].clone()).expect("expect a successful put");
+                ts.delete(&trip_id).expect("successful delete");
+                ts.put(trips[2].clone()).expect("expect a successful put");
+                let trip_id = ts.put(trips[3].clone()).expect("expect a successful put");
+                ts.delete(&trip_id).expect("successful delete");
+                assert_eq!(count_lines(&path), 6);
+            }
+
+            {
+                let mut ts: Series<BikeTrip> =
+                    Series::open_with_options(&path.to_string_lossy(), options.clone())
+                        .expect("expect the time series to open correctly");
+                assert_eq!(count_lines(&path), 2);
+
+                let trip_id = ts.put(trips[4].clone()).expect("expect a successful put");
+                ts.delete(&trip_id).expect("successful delete");
+                assert_eq!(count_lines(&path), 4);
+                let trip_id = ts.put(trips[4].clone()).expect("expect a successful put");
+                ts.delete(&trip_id).expect("successful delete");
+                assert_eq!(count_lines(&path), 2);
+                assert_eq!(ts.all_records().unwrap().len(), 2);
+            }
+        })
+    }
+
+    #[test]
+    pub fn retention_drops_old_records() {
+        run_test(|path| {
+            let now = DateTimeTz::now();
+            let mk_trip = |days_ago: i64| BikeTrip {
+                datetime: now.map(|t| t - chrono::Duration::days(days_ago)),
+                distance: Distance(1000.0 * M),
+                duration: Duration(60.0 * S),
+                comments: format!("{} days ago", days_ago),
+            };
+
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                for days_ago in [45, 40, 10, 1] {
+                    ts.put(mk_trip(days_ago)).expect("expect a successful put");
+                }
+            }
+
+            {
+                let mut ts: Series<BikeTrip> = Series::open_with_options(
+                    &path.to_string_lossy(),
+                    SeriesOptions {
+                        retention: Some(chrono::Duration::days(30)),
+                        ..SeriesOptions::default()
+                    },
+                )
+                .expect("expect the time series to open correctly");
+                assert_eq!(ts.all_records().unwrap().len(), 2);
+                assert_eq!(count_lines(&path), 4);
+
+                ts.compact().expect("compaction should succeed");
+                assert_eq!(count_lines(&path), 2);
+
+                ts.put(mk_trip(50)).expect("expect a successful put");
+                assert_eq!(ts.all_records().unwrap().len(), 2);
+                assert_eq!(count_lines(&path), 3);
+                ts.compact().expect("compaction should succeed");
+                assert_eq!(count_lines(&path), 2);
+            }
+
+            {
+                let ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                let recs = ts.filter(|_| true).unwrap();
+                assert_eq!(recs.len(), 2);
+                assert_eq!(recs[0].data.comments, "10 days ago");
+                assert_eq!(recs[1].data.comments, "1 days ago");
+            }
+        })
+    }
+
+    #[test]
+    pub fn can_expire_records_before_a_time() {
+        run_test(|path| {
+            let trips = mk_trips();
+
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                for trip in &trips[0..=4] {
+                    ts.put(trip.clone()).expect("expect a successful put");
+                }
+
+                let expired = ts
+                    .expire_before(&DateTimeTz(
+                        UTC.with_ymd_and_hms(2011, 11, 2, 0, 0, 0).unwrap(),
+                    ))
+                    .expect("expiry should succeed");
+                assert_eq!(expired, 2);
+                assert_eq!(ts.all_records().unwrap().len(), 3);
+                assert_eq!(count_lines(&path), 7);
+            }
+
+            {
+                let ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                let recs = ts.filter(|_| true).unwrap();
+                assert_eq!(recs.len(), 3);
+                assert_eq!(recs[0].data, trips[2]);
+            }
+        })
+    }
+
+    #[test]
+    pub fn locking_excludes_other_writers() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let read_only = || SeriesOptions {
+                access: Access::ReadOnly,
+                ..SeriesOptions::default()
+            };
+
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                ts.put(trips[0].clone()).expect("expect a successful put");
+
+                match Series::<BikeTrip>::open(&path.to_string_lossy()) {
+                    Err(Error::Locked) => (),
+                    Err(err) => panic!("unexpected error: {}", err),
+                    Ok(_) => panic!("a second writer should not be able to open the series"),
+                }
+                match Series::<BikeTrip>::open_with_options(&path.to_string_lossy(), read_only()) {
+                    Err(Error::Locked) => (),
+                    Err(err) => panic!("unexpected error: {}", err),
+                    Ok(_) => panic!("a reader should not be able to open a locked series"),
+                }
+
+                ts.compact().expect("compaction should succeed");
+                match Series::<BikeTrip>::open(&path.to_string_lossy()) {
+                    Err(Error::Locked) => (),
+                    Err(err) => panic!("unexpected error: {}", err),
+                    Ok(_) => panic!("the series should remain locked after compaction"),
+                }
+            }
+
+            {
+                let mut ts: Series<BikeTrip> =
+                    Series::open_with_options(&path.to_string_lossy(), read_only())
+                        .expect("expect the time series to open correctly");
+                let other: Series<BikeTrip> =
+                    Series::open_with_options(&path.to_string_lossy(), read_only())
+                        .expect("readers should share the lock");
+                assert_eq!(ts.all_records().unwrap().len(), 1);
+                assert_eq!(other.all_records().unwrap().len(), 1);
+                match ts.put(trips[1].clone()) {
+                    Err(Error::ReadOnly) => (),
+                    Err(err) => panic!("unexpected error: {}", err),
+                    Ok(_) => panic!("a read-only series should not accept writes"),
+                }
+                assert_eq!(count_lines(&path), 1);
+            }
+
+            {
+                let mut reader: Series<BikeTrip> =
+                    Series::open_with_options(&path.to_string_lossy(), read_only())
+                        .expect("expect the time series to open correctly");
+                // Move a new file into place, as when a repaired copy replaces the original.
+                let replacement = path.with_extension("new");
+                fs::copy(&path, &replacement).unwrap();
+                fs::rename(&replacement, &path).unwrap();
+                assert_eq!(reader.refresh().unwrap(), 1);
+                match Series::<BikeTrip>::open(&path.to_string_lossy()) {
+                    Err(Error::Locked) => (),
+                    Err(err) => panic!("unexpected error: {}", err),
+                    Ok(_) => panic!("the reader should lock the file that replaced the old one"),
+                }
+            }
+        })
+    }
+
+    #[test]
+    pub fn read_only_series_follows_a_writer() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let mut writer: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            let first = writer
+                .put(trips[0].clone())
+                .expect("expect a successful put");
+
+            let mut reader: Series<BikeTrip> = Series::open_read_only(&path.to_string_lossy())
+                .expect("a reader should open alongside a writer");
+            assert_eq!(reader.all_records().unwrap().len(), 1);
+            assert_eq!(reader.refresh().unwrap(), 0);
+
+            let second = writer
+                .put(trips[1].clone())
+                .expect("expect a successful put");
+            writer
+                .update(Record::with_id(first.clone(), trips[2].clone()))
+                .expect("expect a successful update");
+            writer.delete(&second).expect("expect a successful delete");
+            assert_eq!(reader.refresh().unwrap(), 3);
+            assert_eq!(
+                reader
+                    .search(exact_time(trips[2].datetime.clone()))
+                    .unwrap()[0]
+                    .id,
+                first
+            );
+            assert_eq!(reader.all_records().unwrap().len(), 1);
+
+            let partial = serde_json::to_string(&Record::new(trips[3].clone())).unwrap();
+            {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                f.write_all(&partial.as_bytes()[..10]).unwrap();
+            }
+            assert_eq!(reader.refresh().unwrap(), 0);
+            let late: Series<BikeTrip> = Series::open_read_only(&path.to_string_lossy())
+                .expect("a reader should open while the writer is part way through a line");
+            assert_eq!(late.all_records().unwrap().len(), 1);
+            {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                f.write_all(format!("{}\n", &partial[10..]).as_bytes())
+                    .unwrap();
+            }
+            assert_eq!(reader.refresh().unwrap(), 1);
+            assert_eq!(reader.all_records().unwrap().len(), 2);
+
+            drop(writer);
+            let mut writer: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            writer.compact().expect("compaction should succeed");
+            writer
+                .put(trips[4].clone())
+                .expect("expect a successful put");
+            assert_eq!(reader.refresh().unwrap(), 3);
+            assert_eq!(reader.all_records().unwrap().len(), 3);
+            match reader.put(trips[0].clone()) {
+                Err(Error::ReadOnly) => (),
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("a read-only series should not accept writes"),
+            }
+        })
+    }
+
+    #[test]
+    pub fn failed_writes_leave_the_series_unchanged() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let id = {
+                let mut writer: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                writer
+                    .put(trips[0].clone())
+                    .expect("expect a successful put")
+            };
+
+            let mut reader: Series<BikeTrip> = Series::open_read_only(&path.to_string_lossy())
+                .expect("expect the time series to open read-only");
+            let seq = reader.seq;
+
+            assert!(reader.put(trips[1].clone()).is_err());
+            assert!(reader
+                .update(Record::with_id(id.clone(), trips[2].clone()))
+                .is_err());
+            assert!(reader.delete(&id).is_err());
+            assert!(reader.expire_before(&trips[4].datetime).is_err());
+            assert!(reader
+                .put_batch(vec![trips[3].clone(), trips[4].clone()])
+                .is_err());
+            let mut tx = reader.begin();
+            tx.put(trips[3].clone());
+            tx.delete(&id);
+            assert!(tx.commit().is_err());
+
+            assert_eq!(reader.all_records().unwrap().len(), 1);
+            assert_eq!(reader.get(&id).unwrap().unwrap().data, trips[0]);
+            assert_eq!(reader.seq, seq);
+            assert_eq!(count_lines(&path), 1);
+        })
+    }
+
+    #[test]
+    pub fn failed_appends_are_cut_from_the_file() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            let id = ts.put(trips[0].clone()).expect("expect a successful put");
+            let committed_len = fs::metadata(&path).unwrap().len();
+
+            // Simulate a write that fails after part of it has reached the file.
+            {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                let line = serde_json::to_string(&Record::new(trips[1].clone())).unwrap();
+                f.write_all(format!("{}\n{}", line, &line[..20]).as_bytes())
+                    .unwrap();
+            }
+            let read_only = OpenOptions::new().read(true).open(&path).unwrap();
+            ts.writer = Some(LineWriter::new(read_only));
+            match ts.put_batch(vec![trips[1].clone(), trips[2].clone()]) {
+                Err(Error::IOError(_)) => (),
+                other => panic!("unexpected result: {:?}", other.map_err(|e| e.to_string())),
+            }
+            assert_eq!(fs::metadata(&path).unwrap().len(), committed_len);
+            assert_eq!(ts.all_records().unwrap().len(), 1);
+
+            ts.put(trips[3].clone()).expect("expect a successful put");
+            drop(ts);
+            let ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            let recs = ts.all_records().unwrap();
+            assert_eq!(recs.len(), 2);
+            assert_eq!(ts.get(&id).unwrap().unwrap().data, trips[0]);
+        })
+    }
+
+    #[test]
+    pub fn torn_final_line_is_reported_or_truncated() {
+        run_test(|path| {
+            let trips = mk_trips();
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                ts.put(trips[0].clone()).expect("expect a successful put");
+                ts.put(trips[1].clone()).expect("expect a successful put");
+            }
+            let torn = serde_json::to_string(&Record::new(trips[2].clone())).unwrap();
+            {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                f.write_all(&torn.as_bytes()[..20]).unwrap();
+            }
+
+            match Series::<BikeTrip>::open(&path.to_string_lossy()) {
+                Err(Error::TornWrite { line: 3 }) => (),
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("a torn final line should be reported"),
+            }
+
+            {
+                let mut ts: Series<BikeTrip> = Series::open_with_options(
+                    &path.to_string_lossy(),
+                    SeriesOptions {
+                        torn_write: TornWrite::Truncate,
+                        ..SeriesOptions::default()
+                    },
+                )
+                .expect("expect the time series to open correctly");
+                assert_eq!(ts.all_records().unwrap().len(), 2);
+                assert_eq!(count_lines(&path), 2);
+                ts.put(trips[3].clone()).expect("expect a successful put");
+            }
+
+            let ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            assert_eq!(ts.all_records().unwrap().len(), 3);
+        })
+    }
+
+    #[test]
+    pub fn final_line_without_newline_is_completed() {
+        run_test(|path| {
+            let trips = mk_trips();
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                ts.put(trips[0].clone()).expect("expect a successful put");
+            }
+            // A crash after writing an entry but before its newline.
+            let line = serde_json::to_string(&Record::new(trips[1].clone())).unwrap();
+            {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                f.write_all(line.as_bytes()).unwrap();
+            }
+
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                assert_eq!(ts.all_records().unwrap().len(), 2);
+                ts.put(trips[2].clone()).expect("expect a successful put");
+                ts.put(trips[3].clone()).expect("expect a successful put");
+            }
+            assert_eq!(count_lines(&path), 4);
+
+            for torn_write in [TornWrite::Fail, TornWrite::Truncate] {
+                let ts: Series<BikeTrip> = Series::open_with_options(
+                    &path.to_string_lossy(),
+                    SeriesOptions {
+                        torn_write,
+                        ..SeriesOptions::default()
+                    },
+                )
+                .expect("expect the time series to open correctly");
+                assert_eq!(ts.all_records().unwrap().len(), 4);
+            }
+        })
+    }
+
+    #[test]
+    pub fn repair_salvages_parseable_lines() {
+        run_test(|path| {
+            let trips = mk_trips();
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                ts.put(trips[0].clone()).expect("expect a successful put");
+            }
+            {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                f.write_all(b"{\"id\":\"not a rec").unwrap();
+            }
+            {
+                let mut ts: Series<BikeTrip> = Series::open_with_options(
+                    &path.to_string_lossy(),
+                    SeriesOptions {
+                        torn_write: TornWrite::Truncate,
+                        ..SeriesOptions::default()
+                    },
+                )
+                .expect("expect the time series to open correctly");
+                ts.put(trips[1].clone()).expect("expect a successful put");
+                assert_eq!(count_lines(&path), 2);
+            }
+            {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                f.write_all(b"garbage\n").unwrap();
+                let line = serde_json::to_string(&Record::new(trips[2].clone())).unwrap();
+                f.write_all(format!("{}\n", line).as_bytes()).unwrap();
+            }
+            match Series::<BikeTrip>::open(&path.to_string_lossy()) {
+                Err(Error::JSONParseError(_)) => (),
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("a corrupt line should prevent the series from opening"),
+            }
+            match Series::<BikeTrip>::open_with_options(
+                &path.to_string_lossy(),
+                SeriesOptions {
+                    torn_write: TornWrite::Truncate,
+                    ..SeriesOptions::default()
+                },
+            ) {
+                Err(Error::JSONParseError(_)) => (),
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("a complete corrupt line is not a torn write"),
+            }
+            assert_eq!(count_lines(&path), 4);
+
+            let report =
+                Series::<BikeTrip>::repair(&path.to_string_lossy()).expect("repair should succeed");
+            assert_eq!(report.lines, 4);
+            assert_eq!(report.failed, vec![3]);
+
+            let ts: Series<BikeTrip> =
+                Series::open(&report.salvaged).expect("the salvaged copy should open");
+            assert_eq!(ts.all_records().unwrap().len(), 3);
+            drop(ts);
+            fs::remove_file(&report.salvaged).unwrap();
+        })
+    }
+
+    #[test]
+    pub fn checksums_detect_corrupt_lines() {
+        run_test(|path| {
+            let trips = mk_trips();
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                ts.put(trips[0].clone()).expect("expect a successful put");
+            }
+            {
+                let mut ts: Series<BikeTrip> = Series::open_with_options(
+                    &path.to_string_lossy(),
+                    SeriesOptions {
+                        checksums: true,
+                        ..SeriesOptions::default()
+                    },
+                )
+                .expect("a legacy file should open with checksums enabled");
+                ts.put(trips[1].clone()).expect("expect a successful put");
+                ts.put(trips[2].clone()).expect("expect a successful put");
+            }
+
+            let ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            assert_eq!(ts.all_records().unwrap().len(), 3);
+            drop(ts);
+
+            let contents = fs::read_to_string(&path).unwrap();
+            assert!(contents.lines().next().unwrap().starts_with('{'));
+            assert!(!contents.lines().nth(1).unwrap().starts_with('{'));
+            fs::write(&path, contents.replacen("day 2", "day 3", 1)).unwrap();
+            match Series::<BikeTrip>::open(&path.to_string_lossy()) {
+                Err(Error::ChecksumMismatch { line: 2 }) => (),
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("a corrupt line should be detected"),
+            }
+        })
+    }
+
+    #[test]
+    pub fn durability_policy_controls_syncs() {
+        run_test(|path| {
+            let trips = mk_trips();
+            {
+                let mut ts: Series<BikeTrip> = Series::open_with_options(
+                    &path.to_string_lossy(),
+                    SeriesOptions {
+                        durability: Durability::EveryNWrites(3),
+                        ..SeriesOptions::default()
+                    },
+                )
+                .expect("expect the time series to open correctly");
+                ts.put(trips[0].clone()).expect("expect a successful put");
+                ts.put(trips[1].clone()).expect("expect a successful put");
+                assert_eq!(ts.unsynced, 2);
+                ts.put(trips[2].clone()).expect("expect a successful put");
+                assert_eq!(ts.unsynced, 0);
+                ts.put(trips[3].clone()).expect("expect a successful put");
+                ts.flush_and_sync().expect("sync should succeed");
+                assert_eq!(ts.unsynced, 0);
+            }
+
+            let mut ts: Series<BikeTrip> = Series::open_read_only(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            assert_eq!(ts.all_records().unwrap().len(), 4);
+            match ts.flush_and_sync() {
+                Err(Error::ReadOnly) => (),
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("a read-only series has nothing to sync"),
+            }
+        })
+    }
+
+    #[test]
+    pub fn can_write_batches() {
+        run_test(|path| {
+            let trips = mk_trips();
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                let ids = ts
+                    .put_batch(trips[0..3].iter().cloned())
+                    .expect("expect a successful batch put");
+                assert_eq!(ids.len(), 3);
+                assert_eq!(ts.get(&ids[1]).unwrap().unwrap().data, trips[1]);
+
+                ts.update_batch(vec![
+                    Record::with_id(ids[0].clone(), trips[3].clone()),
+                    Record::with_id(ids[2].clone(), trips[4].clone()),
+                ])
+                .expect("expect a successful batch update");
+                assert_eq!(count_lines(&path), 5);
+            }
+
+            {
+                let mut ts: Series<BikeTrip> = Series::open_read_only(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                let recs: Vec<BikeTrip> = ts
+                    .filter(|_| true)
+                    .unwrap()
+                    .into_iter()
+                    .map(|r| r.data)
+                    .collect();
+                assert_eq!(
+                    recs,
+                    vec![trips[1].clone(), trips[3].clone(), trips[4].clone()]
+                );
+
+                match ts.put_batch(vec![trips[0].clone()]) {
+                    Err(Error::ReadOnly) => (),
+                    Err(err) => panic!("unexpected error: {}", err),
+                    Ok(_) => panic!("a read-only series should not accept writes"),
+                }
+                assert_eq!(ts.all_records().unwrap().len(), 3);
+            }
+        })
+    }
+
+    #[test]
+    pub fn transactions_apply_all_or_nothing() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let (old, corrected) = {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                let old = ts.put(trips[0].clone()).expect("expect a successful put");
+
+                let mut tx = ts.begin();
+                tx.put(trips[4].clone());
+                tx.rollback();
+
+                let mut tx = ts.begin();
+                tx.delete(&old);
+                let corrected = tx.put(trips[1].clone());
+                tx.commit().expect("expect a successful commit");
+
+                assert!(ts.get(&old).unwrap().is_none());
+                assert_eq!(ts.get(&corrected).unwrap().unwrap().data, trips[1]);
+                assert_eq!(count_lines(&path), 5);
+                (old, corrected)
+            };
+
+            // Simulate a crash part way through writing a transaction.
+            let contents = fs::read_to_string(&path).unwrap();
+            let committed_len = contents.len();
+            let lines: Vec<&str> = contents.lines().collect();
+            let begin = lines[1].replace(&lines[1][10..46], &UniqueId::new().to_string());
+            let update =
+                serde_json::to_string(&Record::with_id(corrected.clone(), trips[2].clone()))
+                    .unwrap();
+            {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                f.write_all(format!("{}\n{}\n", begin, update).as_bytes())
+                    .unwrap();
+            }
+
+            {
+                let mut reader: Series<BikeTrip> = Series::open_read_only(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                assert_eq!(reader.get(&corrected).unwrap().unwrap().data, trips[1]);
+                assert_eq!(reader.refresh().unwrap(), 0);
+            }
+
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            assert!(ts.get(&old).unwrap().is_none());
+            assert_eq!(ts.get(&corrected).unwrap().unwrap().data, trips[1]);
+            assert_eq!(fs::metadata(&path).unwrap().len() as usize, committed_len);
+
+            ts.put(trips[3].clone()).expect("expect a successful put");
+            drop(ts);
+            let ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            assert_eq!(ts.all_records().unwrap().len(), 2);
+        })
+    }
+
+    #[test]
+    pub fn transaction_torn_mid_entry_is_uncommitted() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let id = {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                ts.put(trips[0].clone()).expect("expect a successful put")
+            };
+            let committed_len = fs::metadata(&path).unwrap().len();
+
+            // Simulate a crash part way through the entry lines of a transaction.
+            let begin = serde_json::to_string(&Marker::Begin(UniqueId::new())).unwrap();
+            let update =
+                serde_json::to_string(&Record::with_id(id.clone(), trips[1].clone())).unwrap();
+            {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                f.write_all(format!("{}\n{}", begin, &update[..20]).as_bytes())
+                    .unwrap();
+            }
+
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("an uncommitted transaction should not prevent opening");
+            assert_eq!(ts.get(&id).unwrap().unwrap().data, trips[0]);
+            assert_eq!(fs::metadata(&path).unwrap().len(), committed_len);
+
+            ts.put(trips[2].clone()).expect("expect a successful put");
+            drop(ts);
+            let ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            assert_eq!(ts.all_records().unwrap().len(), 2);
+        })
+    }
+
+    #[test]
+    pub fn can_read_history_and_past_states() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            let id = ts.put(trips[0].clone()).expect("expect a successful put");
+            let other = ts.put(trips[1].clone()).expect("expect a successful put");
+            ts.update(Record::with_id(id.clone(), trips[2].clone()))
+                .expect("expect a successful update");
+            let mut tx = ts.begin();
+            tx.delete(&id);
+            tx.commit().expect("expect a successful commit");
+
+            let history: Vec<(u64, Option<BikeTrip>)> = ts
+                .history(&id)
+                .expect("history should be readable")
+                .into_iter()
+                .map(|version| (version.sequence, version.data))
+                .collect();
+            assert_eq!(
+                history,
+                vec![
+                    (1, Some(trips[0].clone())),
+                    (3, Some(trips[2].clone())),
+                    (4, None),
+                ]
+            );
+            assert!(ts.history(&UniqueId::new()).unwrap().is_empty());
+
+            assert!(ts.as_of(0).unwrap().is_empty());
+            let as_of = |seq| -> Vec<BikeTrip> {
+                ts.as_of(seq).unwrap().into_iter().map(|r| r.data).collect()
+            };
+            assert_eq!(as_of(2), vec![trips[0].clone(), trips[1].clone()]);
+            assert_eq!(as_of(3), vec![trips[1].clone(), trips[2].clone()]);
+            assert_eq!(as_of(4), vec![trips[1].clone()]);
+            assert_eq!(ts.as_of(100).unwrap()[0].id, other);
+
+            // Sequence numbers are kept through compaction, and new writes carry on after them.
+            ts.update(Record::with_id(other.clone(), trips[3].clone()))
+                .expect("expect a successful update");
+            ts.compact().expect("compaction should succeed");
+            let history = |ts: &Series<BikeTrip>| -> Vec<(u64, Option<BikeTrip>)> {
+                ts.history(&other)
+                    .unwrap()
+                    .into_iter()
+                    .map(|version| (version.sequence, version.data))
+                    .collect()
+            };
+            assert_eq!(history(&ts), vec![(5, Some(trips[3].clone()))]);
+            assert!(ts.as_of(4).unwrap().is_empty());
+            assert_eq!(ts.as_of(5).unwrap()[0].data, trips[3]);
+            match ts.revert(&other, 2) {
+                Err(Error::NoSuchVersion { sequence: 2, .. }) => (),
+                other => panic!("unexpected result: {:?}", other.map_err(|e| e.to_string())),
+            }
+            drop(ts);
+
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            ts.revert(&other, 5).expect("expect a successful revert");
+            assert_eq!(
+                history(&ts),
+                vec![(5, Some(trips[3].clone())), (6, Some(trips[3].clone()))]
+            );
+
+            // Another process part way through an append does not disturb the history.
+            {
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                f.write_all(b"{\"id\":\"abc").unwrap();
+            }
+            let reader: Series<BikeTrip> = Series::open_read_only(&path.to_string_lossy())
+                .expect("expect the time series to open read-only");
+            for ts in [&ts, &reader] {
+                assert_eq!(history(ts).len(), 2);
+                assert_eq!(ts.as_of(6).unwrap().len(), 1);
+            }
+        })
+    }
+
+    #[test]
+    pub fn can_undelete_and_revert() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            let id = ts.put(trips[0].clone()).expect("expect a successful put");
+            ts.update(Record::with_id(id.clone(), trips[1].clone()))
+                .expect("expect a successful update");
+
+            match ts.undelete(&id) {
+                Err(Error::NotDeleted(_)) => (),
+                other => panic!("unexpected result: {:?}", other.map_err(|e| e.to_string())),
+            }
+            ts.delete(&id).expect("expect a successful delete");
+            ts.undelete(&id).expect("expect a successful undelete");
+            assert_eq!(ts.get(&id).unwrap().unwrap().data, trips[1]);
+
+            ts.revert(&id, 1).expect("expect a successful revert");
+            assert_eq!(ts.get(&id).unwrap().unwrap().data, trips[0]);
+            assert_eq!(ts.history(&id).unwrap().len(), 5);
+
+            match ts.revert(&id, 3) {
+                Err(Error::NoSuchVersion { sequence: 3, .. }) => (),
+                other => panic!("unexpected result: {:?}", other.map_err(|e| e.to_string())),
+            }
+            match ts.revert(&id, 42) {
+                Err(Error::NoSuchVersion { sequence: 42, .. }) => (),
+                other => panic!("unexpected result: {:?}", other.map_err(|e| e.to_string())),
+            }
+            match ts.undelete(&UniqueId::new()) {
+                Err(Error::RecordNotFound(_)) => (),
+                other => panic!("unexpected result: {:?}", other.map_err(|e| e.to_string())),
+            }
+            drop(ts);
+
+            let ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            assert_eq!(ts.get(&id).unwrap().unwrap().data, trips[0]);
+        })
+    }
+
+    #[test]
+    pub fn writes_carry_metadata() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let options = || SeriesOptions {
+                source: Some(String::from("logger")),
+                ..SeriesOptions::default()
+            };
+            let id = {
+                let mut ts: Series<BikeTrip> =
+                    Series::open_with_options(&path.to_string_lossy(), options())
+                        .expect("expect the time series to open correctly");
+                let before = DateTimeTz::now().map(|t| t - chrono::Duration::seconds(1));
+                let id = ts.put(trips[0].clone()).expect("expect a successful put");
+                ts.update(Record::with_id(id.clone(), trips[1].clone()))
+                    .expect("expect a successful update");
+
+                let rec = ts.get(&id).unwrap().unwrap();
+                assert_eq!(rec.sequence(), Some(2));
+                assert_eq!(rec.source(), Some("logger"));
+                let written = rec.written().cloned().unwrap();
+                assert!(written > before);
+
+                let history = ts.history(&id).unwrap();
+                assert_eq!(history[0].meta.seq, Some(1));
+                assert_eq!(history[1].meta, *rec.metadata());
+
+                assert!(ts.as_of_time(&before).unwrap().is_empty());
+                assert_eq!(ts.as_of_time(&written).unwrap()[0].data, trips[1]);
+                ts.compact().expect("compaction should succeed");
+                id
+            };
+
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            assert_eq!(ts.get(&id).unwrap().unwrap().sequence(), Some(2));
+            let other = ts.put(trips[2].clone()).expect("expect a successful put");
+            let rec = ts.get(&other).unwrap().unwrap();
+            assert_eq!(rec.sequence(), Some(3));
+            assert_eq!(rec.source(), None);
+        })
+    }
+
+    #[test]
+    pub fn legacy_lines_are_numbered_without_other_metadata() {
+        run_test(|path| {
+            fs::copy("fixtures/weight.json", &path).unwrap();
+            let ts: Series<WeightRecord> =
+                Series::open(&path.to_string_lossy()).expect("legacy series should open correctly");
+            let uid = "3330c5b0-783f-4919-b2c4-8169c38f65ff".parse().unwrap();
+            let rec = ts.get(&uid).unwrap().unwrap();
+            assert_eq!(rec.sequence(), Some(1));
+            assert_eq!(rec.written(), None);
+            assert_eq!(rec.source(), None);
+            assert_eq!(rec.metadata().schema, None);
+            assert!(ts.header().is_none());
+        })
+    }
+
+    #[test]
+    pub fn as_of_time_follows_write_times_through_compaction() {
+        run_test(|path| {
+            let trips = mk_trips();
+            let written = |hour| DateTimeTz(UTC.with_ymd_and_hms(2020, 1, 1, hour, 0, 0).unwrap());
+            {
+                // The later trip was logged first, so compaction reorders the lines.
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                for (seq, trip) in [(1, &trips[4]), (2, &trips[0])] {
+                    let entry = DeletableRecord {
+                        id: UniqueId::new(),
+                        data: Some(trip.clone()),
+                        meta: Metadata {
+                            seq: Some(seq),
+                            written: Some(written(seq as u32)),
+                            ..Metadata::default()
+                        },
+                    };
+                    f.write_all(format!("{}\n", serde_json::to_string(&entry).unwrap()).as_bytes())
+                        .unwrap();
+                }
+            }
+
+            let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                .expect("expect the time series to open correctly");
+            let comments = |ts: &Series<BikeTrip>, hour| -> Vec<String> {
+                ts.as_of_time(&written(hour))
+                    .unwrap()
+                    .into_iter()
+                    .map(|r| r.data.comments)
+                    .collect()
+            };
+            assert_eq!(comments(&ts, 1), vec!["day 5"]);
+            ts.compact().expect("compaction should succeed");
+            assert_eq!(comments(&ts, 0), Vec::<String>::new());
+            assert_eq!(comments(&ts, 1), vec!["day 5"]);
+            assert_eq!(comments(&ts, 2), vec!["long time ago", "day 5"]);
+        })
+    }
+
+    #[test]
+    pub fn header_is_written_and_checked() {
+        run_test(|path| {
+            let trips = mk_trips();
+            {
+                let mut ts: Series<BikeTrip> = Series::open_with_options(
+                    &path.to_string_lossy(),
+                    SeriesOptions {
+                        header: true,
+                        ..SeriesOptions::default()
+                    },
+                )
+                .expect("expect the time series to open correctly");
+                ts.put(trips[0].clone()).expect("expect a successful put");
+                ts.put(trips[1].clone()).expect("expect a successful put");
+                ts.compact().expect("compaction should succeed");
+                assert_eq!(count_lines(&path), 3);
+            }
+
+            let contents = fs::read_to_string(&path).unwrap();
+            assert!(contents.starts_with("{\"header\":"));
+            {
+                let ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                let header = ts.header().expect("the file should have a header");
+                assert_eq!(header.format, FORMAT_VERSION);
+                assert!(header.type_name.as_ref().unwrap().ends_with("BikeTrip"));
+                assert_eq!(ts.all_records().unwrap().len(), 2);
+            }
+
+            let newer = contents.replacen(
+                &format!("\"format\":{}", FORMAT_VERSION),
+                &format!("\"format\":{}", FORMAT_VERSION + 1),
+                1,
+            );
+            fs::write(&path, newer).unwrap();
+            match Series::<BikeTrip>::open(&path.to_string_lossy()) {
+                Err(Error::UnsupportedFormat(_)) => (),
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("a newer format should be refused"),
+            }
+
+            fs::write(
+                &path,
+                "{\"header\":{\"format\":1,\"compression\":\"zstd\"}}\n",
+            )
+            .unwrap();
+            match Series::<BikeTrip>::open(&path.to_string_lossy()) {
+                Err(Error::UnsupportedFormat(_)) => (),
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("compressed files should be refused"),
+            }
+
+            fs::write(&path, "").unwrap();
+            {
+                let mut ts: Series<BikeTrip> = Series::open_with_options(
+                    &path.to_string_lossy(),
+                    SeriesOptions {
+                        header: true,
+                        checksums: true,
+                        ..SeriesOptions::default()
+                    },
+                )
+                .expect("expect the time series to open correctly");
+                ts.put(trips[0].clone()).expect("expect a successful put");
+            }
+            {
+                let mut ts: Series<BikeTrip> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                ts.put(trips[1].clone()).expect("expect a successful put");
+            }
+            let contents = fs::read_to_string(&path).unwrap();
+            assert_eq!(contents.lines().count(), 3);
+            assert!(contents.lines().all(|line| !line.starts_with('{')));
+        })
+    }
+
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    pub struct Weight(Kilogram<f64>);
+
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    pub struct WeightRecord {
+        pub date: DateTimeTz,
+        pub weight: Weight,
+    }
+
+    impl Recordable for WeightRecord {
+        fn timestamp(&self) -> DateTimeTz {
+            self.date.clone()
+        }
+
+        fn tags(&self) -> Vec<String> {
+            Vec::new()
+        }
+    }
+
+    #[test]
+    pub fn legacy_file_load() {
+        let ts: Series<WeightRecord> =
+            Series::open("fixtures/weight.json").expect("legacy series should open correctly");
+
+        let uid = "3330c5b0-783f-4919-b2c4-8169c38f65ff"
+            .parse()
+            .expect("something is wrong with this ID");
+        let rec = ts.get(&uid);
+        match rec {
+            Err(err) => assert!(false, "{}", err),
+            Ok(None) => assert!(false, "no record found"),
+            Ok(Some(rec)) => assert_eq!(rec.data.weight, Weight(77.79109 * KG)),
+        }
+    }
+
+    #[test]
+    pub fn refresh_renumbers_a_replaced_file() {
+        run_test(|path| {
+            {
+                let mut ts: Series<WeightRecord> = Series::open(&path.to_string_lossy())
+                    .expect("expect the time series to open correctly");
+                for hour in [6, 7] {
+                    ts.put(WeightRecord {
+                        date: DateTimeTz(UTC.with_ymd_and_hms(2003, 11, 12, hour, 0, 0).unwrap()),
+                        weight: Weight(77.0 * KG),
+                    })
+                    .expect("expect a successful put");
+                }
+            }
+            let mut reader: Series<WeightRecord> = Series::open_read_only(&path.to_string_lossy())
+                .expect("expect the time series to open read-only");
+
+            // Move a legacy file into place, as when a repaired copy replaces the original.
+            let replacement = path.with_extension("new");
+            fs::copy("fixtures/weight.json", &replacement).unwrap();
+            fs::rename(&replacement, &path).unwrap();
+            reader.refresh().expect("the replaced file should load");
+
+            let uid = "3330c5b0-783f-4919-b2c4-8169c38f65ff".parse().unwrap();
+            assert_eq!(reader.get(&uid).unwrap().unwrap().sequence(), Some(1));
+            assert_eq!(reader.history(&uid).unwrap()[0].sequence, 1);
+        })
+    }
+
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+    pub struct WeightRecordV2 {
+        pub date: DateTimeTz,
+        pub weight: Weight,
+        pub notes: String,
+    }
+
+    impl Recordable for WeightRecordV2 {
+        fn timestamp(&self) -> DateTimeTz {
+            self.date.clone()
+        }
+
+        fn tags(&self) -> Vec<String> {
+            Vec::new()
+        }
+    }
+
+    fn add_notes(mut data: serde_json::Value) -> serde_json::Value {
+        data["notes"] = serde_json::Value::String(String::from("imported"));
+        data
+    }
+
+    #[test]
+    pub fn legacy_records_are_migrated() {
+        run_test(|path| {
+            fs::copy("fixtures/weight.json", &path).unwrap();
+            let uid: UniqueId = "3330c5b0-783f-4919-b2c4-8169c38f65ff".parse().unwrap();
+            let options = |rewrite_migrated| SeriesOptions {
+                migrations: Migrations::new().then(add_notes),
+                rewrite_migrated,
+                ..SeriesOptions::default()
+            };
+
+            match Series::<WeightRecordV2>::open(&path.to_string_lossy()) {
+                Err(Error::JSONParseError(_)) => (),
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("old records should not load without a migration"),
+            }
+
+            {
+                let mut ts: Series<WeightRecordV2> =
+                    Series::open_with_options(&path.to_string_lossy(), options(false))
+                        .expect("legacy records should be migrated");
+                let rec = ts.get(&uid).unwrap().unwrap();
+                assert_eq!(rec.data.notes, "imported");
+                assert_eq!(rec.metadata().schema, None);
+
+                let id = ts
+                    .put(WeightRecordV2 {
+                        date: DateTimeTz(UTC.with_ymd_and_hms(2003, 11, 12, 6, 0, 0).unwrap()),
+                        weight: Weight(77.5 * KG),
+                        notes: String::from("after breakfast"),
+                    })
+                    .expect("expect a successful put");
+                assert_eq!(ts.get(&id).unwrap().unwrap().metadata().schema, Some(1));
+            }
+
+            {
+                let ts: Series<WeightRecordV2> =
+                    Series::open_with_options(&path.to_string_lossy(), options(true))
+                        .expect("legacy records should be migrated");
+                assert_eq!(ts.all_records().unwrap().len(), 3);
+            }
+            let contents = fs::read_to_string(&path).unwrap();
+            assert_eq!(contents.lines().count(), 3);
+            assert!(contents.lines().all(|line| line.contains("\"schema\":1")));
+
+            match Series::<WeightRecordV2>::open(&path.to_string_lossy()) {
+                Err(Error::UnsupportedFormat(_)) => (),
+                Err(err) => panic!("unexpected error: {}", err),
+                Ok(_) => panic!("records newer than the registered migrations should be refused"),
+            }
+            let ts: Series<WeightRecordV2> =
+                Series::open_with_options(&path.to_string_lossy(), options(false))
+                    .expect("expect the time series to open correctly");
+            assert_eq!(ts.get(&uid).unwrap().unwrap().data.notes, "imported");
+        })
     }
 }
@@ -0,0 +1,63 @@
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+
+use crate::series::Series;
+use crate::types::{DeletableRecord, Error, Metadata, Record, Recordable, UniqueId};
+
+/// A set of changes to a series that are written and applied together. Create one with
+/// `Series::begin`.
+///
+/// Nothing is written to the file, and the series does not change, until `commit` is called.
+/// Dropping a transaction without committing it discards its changes, as does `rollback`.
+pub struct Transaction<'a, T: Clone + Recordable + DeserializeOwned + Serialize> {
+    series: &'a mut Series<T>,
+    entries: Vec<DeletableRecord<T>>,
+}
+
+impl<'a, T> Transaction<'a, T>
+where
+    T: Clone + Recordable + DeserializeOwned + Serialize,
+{
+    pub(crate) fn new(series: &'a mut Series<T>) -> Transaction<'a, T> {
+        Transaction {
+            series,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Add a new record as part of the transaction, returning the id that it will have once the
+    /// transaction is committed.
+    pub fn put(&mut self, entry: T) -> UniqueId {
+        let record = Record::new(entry);
+        let id = record.id.clone();
+        self.update(record);
+        id
+    }
+
+    /// Update an existing record as part of the transaction.
+    pub fn update(&mut self, record: Record<T>) {
+        self.entries.push(DeletableRecord {
+            id: record.id,
+            data: Some(record.data),
+            meta: Metadata::default(),
+        });
+    }
+
+    /// Delete a record as part of the transaction.
+    pub fn delete(&mut self, id: &UniqueId) {
+        self.entries.push(DeletableRecord {
+            id: id.clone(),
+            data: None,
+            meta: Metadata::default(),
+        });
+    }
+
+    /// Write all of the changes to the file in a single append, between begin and commit
+    /// markers, and then apply them to the series.
+    pub fn commit(self) -> Result<(), Error> {
+        self.series.commit_entries(self.entries)
+    }
+
+    /// Discard all of the changes.
+    pub fn rollback(self) {}
+}
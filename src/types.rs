@@ -1,4 +1,5 @@
 extern crate chrono;
+extern crate regex;
 extern crate serde;
 extern crate serde_json;
 extern crate uuid;
@@ -7,6 +8,7 @@ use self::serde::de::DeserializeOwned;
 use self::serde::ser::Serialize;
 use self::uuid::Uuid;
 use crate::date_time_tz::DateTimeTz;
+use crate::options::Cursor;
 use std::error;
 use std::fmt;
 use std::io;
@@ -26,6 +28,45 @@ pub enum Error {
 
     /// Indicates a general IO error
     IOError(io::Error),
+
+    /// Indicates that the final line of the series file could not be parsed, which usually means
+    /// that a write was interrupted part way through. `line` is the 1-based line number.
+    TornWrite { line: usize },
+
+    /// Indicates that a line of the series file does not match its checksum. `line` is the 1-based
+    /// line number.
+    ChecksumMismatch { line: usize },
+
+    /// Indicates that there is no record with this id, nor any version of one in the file
+    RecordNotFound(UniqueId),
+
+    /// Indicates an attempt to undelete a record that has not been deleted
+    NotDeleted(UniqueId),
+
+    /// Indicates that the record has no version with this sequence number, or that the version is
+    /// a deletion
+    NoSuchVersion { id: UniqueId, sequence: u64 },
+
+    /// Indicates that the series file was written in a format that this version of the library
+    /// cannot read, as described in its header
+    UnsupportedFormat(String),
+
+    /// Indicates that another process holds a conflicting lock on the series file
+    Locked,
+
+    /// Indicates an attempt to write to a series that was opened read-only
+    ReadOnly,
+
+    /// Indicates that a regular expression is invalid and cannot be compiled
+    RegexParseError(regex::Error),
+
+    /// Indicates that a textual query could not be parsed. `position` is the byte offset into the
+    /// query at which the problem was found.
+    QueryParseError {
+        position: usize,
+        expected: String,
+        found: String,
+    },
 }
 
 impl fmt::Display for Error {
@@ -35,6 +76,40 @@ impl fmt::Display for Error {
             Error::JSONStringError(err) => write!(f, "Error generating a JSON string: {}", err),
             Error::JSONParseError(err) => write!(f, "Error parsing JSON: {}", err),
             Error::IOError(err) => write!(f, "IO Error: {}", err),
+            Error::TornWrite { line } => write!(
+                f,
+                "The final line of the series file, line {}, is incomplete or corrupt",
+                line
+            ),
+            Error::ChecksumMismatch { line } => {
+                write!(
+                    f,
+                    "Line {} of the series file does not match its checksum",
+                    line
+                )
+            }
+            Error::RecordNotFound(id) => write!(f, "No version of record {} was found", id),
+            Error::NotDeleted(id) => write!(f, "Record {} has not been deleted", id),
+            Error::NoSuchVersion { id, sequence } => write!(
+                f,
+                "Record {} has no version with sequence number {}",
+                id, sequence
+            ),
+            Error::UnsupportedFormat(reason) => {
+                write!(f, "The series file cannot be read: {}", reason)
+            }
+            Error::Locked => write!(f, "The series file is locked by another process"),
+            Error::ReadOnly => write!(f, "The series was opened read-only"),
+            Error::RegexParseError(err) => write!(f, "Regular expression failed to parse: {}", err),
+            Error::QueryParseError {
+                position,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Error parsing query at position {}: expected {}, found {}",
+                position, expected, found
+            ),
         }
     }
 }
@@ -46,10 +121,34 @@ impl error::Error for Error {
             Error::JSONStringError(ref err) => Some(err),
             Error::JSONParseError(ref err) => Some(err),
             Error::IOError(ref err) => Some(err),
+            Error::TornWrite { .. } => None,
+            Error::ChecksumMismatch { .. } => None,
+            Error::RecordNotFound(_) => None,
+            Error::NotDeleted(_) => None,
+            Error::NoSuchVersion { .. } => None,
+            Error::UnsupportedFormat(_) => None,
+            Error::Locked => None,
+            Error::ReadOnly => None,
+            Error::RegexParseError(ref err) => Some(err),
+            Error::QueryParseError { .. } => None,
         }
     }
 }
 
+/// The outcome of `Series::repair`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepairReport {
+    /// The total number of lines in the original file.
+    pub lines: usize,
+
+    /// The 1-based numbers of the lines that failed to parse, and were left out of the salvaged
+    /// copy.
+    pub failed: Vec<usize>,
+
+    /// The path of the salvaged copy.
+    pub salvaged: String,
+}
+
 /// Any element to be put into the database needs to be Recordable. This is the common API that
 /// will aid in searching and later in indexing records.
 pub trait Recordable {
@@ -63,7 +162,7 @@ pub trait Recordable {
 /// Uniquely identifies a record.
 ///
 /// This is a wrapper around a basic uuid with some extra convenience methods.
-#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub struct UniqueId(Uuid);
 
 impl UniqueId {
@@ -98,6 +197,8 @@ impl fmt::Display for UniqueId {
 pub struct Record<T: Clone + Recordable> {
     pub id: UniqueId,
     pub data: T,
+    #[serde(skip)]
+    pub(crate) meta: Metadata,
 }
 
 impl<T> Record<T>
@@ -105,8 +206,46 @@ where
     T: Clone + Recordable,
 {
     pub fn new(data: T) -> Record<T> {
-        let id = UniqueId::new();
-        Record { id, data }
+        Record::with_id(UniqueId::new(), data)
+    }
+
+    /// A record with an existing id, typically to pass to `Series::update`.
+    pub fn with_id(id: UniqueId, data: T) -> Record<T> {
+        Record {
+            id,
+            data,
+            meta: Metadata::default(),
+        }
+    }
+
+    /// The sequence number of the write that produced this version of the record, or None if it
+    /// has not been written yet.
+    pub fn sequence(&self) -> Option<u64> {
+        self.meta.seq
+    }
+
+    /// When this version of the record was written, or None if it has not been written yet or was
+    /// written by an older version of this library.
+    pub fn written(&self) -> Option<&DateTimeTz> {
+        self.meta.written.as_ref()
+    }
+
+    /// The `source` option of the series that wrote this version of the record, if it had one.
+    pub fn source(&self) -> Option<&str> {
+        self.meta.source.as_deref()
+    }
+
+    /// Everything recorded about the write that produced this version of the record.
+    pub fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+
+    /// The position of this record, for use in paging through search results.
+    pub fn cursor(&self) -> Cursor {
+        Cursor {
+            timestamp: self.data.timestamp(),
+            id: self.id.clone(),
+        }
     }
 }
 
@@ -126,6 +265,113 @@ where
 pub struct DeletableRecord<T: Clone + Recordable> {
     pub id: UniqueId,
     pub data: Option<T>,
+    #[serde(flatten)]
+    pub meta: Metadata,
+}
+
+/// Information about when and by whom a line of the series file was written. Every field is
+/// optional, since lines written by older versions of this library carry none of it.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Metadata {
+    /// The position of the write in the series file. Sequence numbers start at 1 and increase
+    /// with every write, and survive compaction. A line written by an older version of this
+    /// library is numbered after the lines before it as it is loaded, and keeps that number when
+    /// the file is compacted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+
+    /// The wall-clock time at which the line was written, to the second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub written: Option<DateTimeTz>,
+
+    /// The `source` option of the series that wrote the line, identifying the author or process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The version of the record type that the data was written with, as counted by
+    /// `Migrations`. Absent means version 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<u32>,
+}
+
+/// One version of a record, as returned by `Series::history`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version<T> {
+    /// The sequence number of the write that produced this version, as in `Metadata::seq`. It is
+    /// unchanged by compaction, so it can be passed to `Series::revert` or `Series::as_of` later.
+    pub sequence: u64,
+
+    /// The record data, or None if this version is a deletion.
+    pub data: Option<T>,
+
+    /// When and by whom this version was written.
+    pub meta: Metadata,
+}
+
+/// A line of the series file that brackets the entries written by a transaction. The entries
+/// between a `Begin` and the `Commit` with the same id take effect only once the commit has been
+/// written.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Marker {
+    #[serde(rename = "begin")]
+    Begin(UniqueId),
+    #[serde(rename = "commit")]
+    Commit(UniqueId),
+}
+
+/// Any single line of the series file.
+pub enum Entry<T: Clone + Recordable> {
+    Header(Header),
+    Record(DeletableRecord<T>),
+    Marker(Marker),
+}
+
+/// The version of the series file format that this library writes.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The optional first line of a series file, which describes how the rest of the file is written.
+/// It is written as `{"header": {...}}`. Files without a header are read as format version 1.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Header {
+    /// The version of the file format. Files with a newer version than `FORMAT_VERSION` cannot be
+    /// opened.
+    pub format: u32,
+
+    /// The name of the record type that the file was created for. This is for information only,
+    /// since type names change when code is moved around.
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_name: Option<String>,
+
+    /// When the file was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTimeTz>,
+
+    /// Whether the file was created with checksums enabled.
+    #[serde(default)]
+    pub checksums: bool,
+
+    /// The compression applied to the entries in the file. This library does not compress
+    /// entries, so only files without compression can be opened.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+}
+
+impl Header {
+    /// Check that a file with this header can be read. Raise UnsupportedFormat if it cannot.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.format > FORMAT_VERSION {
+            return Err(Error::UnsupportedFormat(format!(
+                "format version {} is newer than the supported version {}",
+                self.format, FORMAT_VERSION
+            )));
+        }
+        if let Some(ref compression) = self.compression {
+            return Err(Error::UnsupportedFormat(format!(
+                "compression {} is not supported",
+                compression
+            )));
+        }
+        Ok(())
+    }
 }
 
 impl<T> str::FromStr for DeletableRecord<T>